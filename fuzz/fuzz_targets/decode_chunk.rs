@@ -0,0 +1,18 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use mayakashi::proto;
+
+// 先頭 6 バイトを chunk の情報 (compressed_method, original_length, using_dictionary) に、残りを圧縮されたデータにする
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 6 {
+        return;
+    }
+    let chunk = proto::ChunkInfo {
+        compressed_method: data[0] as i32 % 4,
+        original_length: u32::from_be_bytes([data[1], data[2], data[3], data[4]]),
+        using_dictionary: data[5] & 1 != 0,
+        compressed_length: (data.len() - 6) as u32,
+    };
+    let _ = mayakashi::format::body::decode_chunk(&chunk, &data[6..], &[]);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// 壊れた (or 悪意のある) .idx で panic したり巨大なメモリ確保をしたりしないこと
+fuzz_target!(|data: &[u8]| {
+    let _ = mayakashi::format::index_file::parse_index_file(&mut &data[..]);
+});
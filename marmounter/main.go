@@ -30,13 +30,16 @@ import (
 )
 
 const INDEX_MAGIC = "MARI"
+const INDEX_MAGIC_WITH_METHOD = "MAR2"
 const WHITEOUT_SUFFIX = ".__whiteout__"
 const WRITEBACK_SUFFIX = ".__writeback__"
 
 type FileInfo struct {
-	MarEntry    *pb.FileEntry
-	ZipEntry    *zip.File
-	ArchiveFile string
+	MarEntry       *pb.FileEntry
+	ZipEntry       *zip.File
+	ArchiveFile    string
+	// zstd_dictionary of the index MarEntry came from (for chunks with using_dictionary)
+	ZstdDictionary []byte
 }
 
 type DirInfo struct {
@@ -98,6 +101,14 @@ func recoverHandler() {
 	}
 }
 
+// UTF-8 でないパスは Path が置き換え文字入りになっていて、元のバイト列は RawPath にある
+func MarPath(path string, raw []byte) string {
+	if len(raw) > 0 {
+		return string(raw)
+	}
+	return path
+}
+
 func NormalizeString(s string) string {
 	s = strings.ToLower(s)
 	s = norm.NFC.String(s)
@@ -370,7 +381,14 @@ func (fs *MayakashiFS) parseMARFile(file string, o ArchiveReadOptions) error {
 		return err
 	}
 
-	if string(magic) != INDEX_MAGIC {
+	method := pb.CompressedMethod_ZSTANDARD
+	if string(magic) == INDEX_MAGIC_WITH_METHOD {
+		methodByte := make([]byte, 1)
+		if _, err := io.ReadFull(f, methodByte); err != nil {
+			return err
+		}
+		method = pb.CompressedMethod(methodByte[0])
+	} else if string(magic) != INDEX_MAGIC {
 		panic("invalid magic")
 	}
 
@@ -392,14 +410,24 @@ func (fs *MayakashiFS) parseMARFile(file string, o ArchiveReadOptions) error {
 		return err
 	}
 
-	decoder, err := zstd.NewReader(nil, zstd.WithDecoderConcurrency(0))
-	if err != nil {
-		return err
-	}
+	if method == pb.CompressedMethod_ZSTANDARD {
+		decoder, err := zstd.NewReader(nil, zstd.WithDecoderConcurrency(0))
+		if err != nil {
+			return err
+		}
 
-	data, err = decoder.DecodeAll(data, make([]byte, 0, int(decompressedLength)))
-	if err != nil {
-		return err
+		data, err = decoder.DecodeAll(data, make([]byte, 0, int(decompressedLength)))
+		if err != nil {
+			return err
+		}
+	} else if method == pb.CompressedMethod_LZ4 {
+		decoded := make([]byte, decompressedLength)
+		if _, err := lz4.UncompressBlock(data, decoded); err != nil {
+			return err
+		}
+		data = decoded
+	} else if method != pb.CompressedMethod_PASSTHROUGH {
+		return fmt.Errorf("unknown index compression method: %d", method)
 	}
 
 	var indexFile pb.FileIndexFile
@@ -411,7 +439,7 @@ func (fs *MayakashiFS) parseMARFile(file string, o ArchiveReadOptions) error {
 
 	ourFiles := map[string]struct{}{}
 	for _, entry := range indexFile.Entries {
-		origPath := o.GetFilePath(entry.Info.Path)
+		origPath := o.GetFilePath(MarPath(entry.Info.Path, entry.Info.RawPath))
 		if origPath == "" {
 			continue
 		}
@@ -434,13 +462,22 @@ func (fs *MayakashiFS) parseMARFile(file string, o ArchiveReadOptions) error {
 		ourFiles[lowerPath] = struct{}{}
 
 		fs.Files[lowerPath] = FileInfo{
-			MarEntry:    entry,
-			ArchiveFile: file,
+			MarEntry:       entry,
+			ArchiveFile:    file,
+			ZstdDictionary: indexFile.ZstdDictionary,
 		}
 
 		fs.Directories[fs.getDirInfo(dir)].Files[NormalizeString(origPath)] = origPath
 		fileCount += 1
 	}
+	// 空のディレクトリも見えるようにする (古いアーカイブには無い)
+	for _, dir := range indexFile.Directories {
+		dirPath := o.GetFilePath(MarPath(dir.Path, dir.RawPath))
+		if dirPath == "" {
+			continue
+		}
+		fs.getDirInfo(dirPath)
+	}
 	fmt.Printf("Loaded %d files\n", fileCount)
 
 	return nil
@@ -488,8 +525,18 @@ func GetFuseStatFromMarEntry(e *pb.FileEntry, stat *fuse.Stat_t) {
 	for _, chunk := range e.Info.Chunks {
 		size += int64(chunk.OriginalLength)
 	}
+	if e.InSolidBlob {
+		size = int64(e.SolidLength)
+	}
 	stat.Mode = fuse.S_IFREG | 0777
+	if e.Info.Mode != 0 {
+		stat.Mode = fuse.S_IFREG | e.Info.Mode
+	}
 	stat.Size = size
+	if e.Info.SymlinkTarget != "" {
+		stat.Mode = fuse.S_IFLNK | 0777
+		stat.Size = int64(len(MarPath(e.Info.SymlinkTarget, e.Info.RawSymlinkTarget)))
+	}
 	time := fuse.NewTimespec(e.Info.ModifiedTime.AsTime())
 	stat.Ctim = time
 	stat.Mtim = time
@@ -514,7 +561,7 @@ func GetFuseStatFromFileInfo(fi *FileInfo, stat *fuse.Stat_t) {
 func (fi *FileInfo) GetFilename() string {
 	var path string
 	if fi.MarEntry != nil {
-		path = fi.MarEntry.Info.Path
+		path = MarPath(fi.MarEntry.Info.Path, fi.MarEntry.Info.RawPath)
 	} else {
 		path = FixPathSplitter(fi.ZipEntry.Name)
 	}
@@ -582,6 +629,14 @@ func (fs *MayakashiFS) Getattr(path string, stat *fuse.Stat_t, fh uint64) int {
 	return -fuse.ENOENT
 }
 
+func (fs *MayakashiFS) Readlink(path string) (int, string) {
+	defer recoverHandler()
+	if file, ok := fs.Files[NormalizeString(path)]; ok && file.MarEntry != nil && file.MarEntry.Info.SymlinkTarget != "" {
+		return 0, MarPath(file.MarEntry.Info.SymlinkTarget, file.MarEntry.Info.RawSymlinkTarget)
+	}
+	return -fuse.EINVAL, ""
+}
+
 func (fs *MayakashiFS) Readdir(path string,
 	fill func(name string, stat *fuse.Stat_t, ofst int64) bool,
 	ofst int64,
@@ -886,6 +941,17 @@ func (fs *MayakashiFS) readInternalFromZipEntry(path string, buff []byte, offset
 
 func (fs *MayakashiFS) readInternalFromMarEntry(path string, buff []byte, offset int64, fh uint64, file *FileInfo) int {
 	entry := file.MarEntry
+	if entry.InSolidBlob {
+		// the body is a blob shared with other files, so read our range of it
+		remains := int64(entry.SolidLength) - offset
+		if remains <= 0 {
+			return 0
+		}
+		if int64(len(buff)) > remains {
+			buff = buff[:remains]
+		}
+		offset += int64(entry.SolidOffset)
+	}
 	chunkStart := int64(0)
 	datStart := int64(entry.BodyOffset)
 	chunkNo := -1
@@ -927,7 +993,7 @@ func (fs *MayakashiFS) readInternalFromMarEntry(path string, buff []byte, offset
 			compressedBytes := make([]byte, targetChunk.CompressedLength)
 			start := time.Now()
 			fs.LastDatRead = start
-			if _, err := pool.ReadAt(compressedBytes, datStart); err != nil {
+			if _, err := readMarEntryBody(pool, entry, compressedBytes, datStart); err != nil {
 				println("failed to ReadAt compressed data", err)
 				return -fuse.EIO
 			}
@@ -936,7 +1002,7 @@ func (fs *MayakashiFS) readInternalFromMarEntry(path string, buff []byte, offset
 				fs.SlowReadLog.Write([]byte(path + "\n"))
 			}
 
-			res := fs.readChunk(targetChunk, &compressedBytes, &decoded)
+			res := fs.readChunk(targetChunk, file.ZstdDictionary, &compressedBytes, &decoded)
 			if res != 0 {
 				return res
 			}
@@ -967,7 +1033,7 @@ func (fs *MayakashiFS) readInternalFromMarEntry(path string, buff []byte, offset
 		fmt.Println("!!!OVERLOAD!!!", len(buff), remainsLength)
 		buff = buff[:remainsLength]
 	}
-	readed, err := pool.ReadAt(buff, datStart+(offset-chunkStart))
+	readed, err := readMarEntryBody(pool, entry, buff, datStart+(offset-chunkStart))
 	if err != nil {
 		fmt.Println("failed to read from passthrough", err)
 		return -fuse.EIO
@@ -975,9 +1041,25 @@ func (fs *MayakashiFS) readInternalFromMarEntry(path string, buff []byte, offset
 	return readed
 }
 
-func (fs *MayakashiFS) readChunk(targetChunk *pb.ChunkInfo, compressedBytes *[]byte, decoded *[]byte) int {
+// reads body bytes at the .dat offset, or from inline_body if the body is stored in the index
+func readMarEntryBody(pool *FilePool, entry *pb.FileEntry, b []byte, off int64) (int, error) {
+	if len(entry.InlineBody) > 0 {
+		return copy(b, entry.InlineBody[off-int64(entry.BodyOffset):]), nil
+	}
+	return pool.ReadAt(b, off)
+}
+
+func (fs *MayakashiFS) readChunk(targetChunk *pb.ChunkInfo, dictionary []byte, compressedBytes *[]byte, decoded *[]byte) int {
 	if targetChunk.CompressedMethod == pb.CompressedMethod_ZSTANDARD {
-		decoder, err := zstd.NewReader(nil, zstd.WithDecoderConcurrency(0))
+		options := []zstd.DOption{zstd.WithDecoderConcurrency(0)}
+		if targetChunk.UsingDictionary {
+			if len(dictionary) == 0 {
+				println("chunk uses a dictionary, but the index has none")
+				return -fuse.EIO
+			}
+			options = append(options, zstd.WithDecoderDicts(dictionary))
+		}
+		decoder, err := zstd.NewReader(nil, options...)
 		if err != nil {
 			println("failed to read", err)
 			return -fuse.EIO
@@ -1318,7 +1400,7 @@ func main() {
 				}
 				var marFileName string
 				entry := file.MarEntry
-				if entry == nil {
+				if entry == nil || len(entry.InlineBody) > 0 {
 					continue
 				}
 				if entry.FileIndex == 0 {
@@ -0,0 +1,47 @@
+use std::io::{BufWriter, Write};
+
+use clap::Parser;
+
+use crate::error::MarError;
+
+#[derive(Parser)]
+pub struct Args {
+    /// Archive to read (the name given to `create --output`)
+    #[arg(short, long)]
+    input: std::path::PathBuf,
+
+    /// Path of the file in the archive (the leading / is optional)
+    path: String,
+
+    /// Only write the bytes from this offset on (only the chunks covering the range are decompressed)
+    #[arg(long)]
+    start: Option<u64>,
+
+    /// Only write the bytes before this offset (exclusive)
+    #[arg(long)]
+    end: Option<u64>,
+
+    /// Memory-map the .dat files instead of reading each body with read(2) (same as --read-strategy mmap)
+    #[arg(long)]
+    mmap: bool,
+}
+
+pub fn main(args: Args) -> Result<(), MarError> {
+    let reader = crate::cmd::open_reader(&args.input, args.mmap)?;
+    let entry = reader.find(&args.path).ok_or_else(|| MarError::NotFound { path: args.path.clone() })?;
+
+    let mut out = BufWriter::new(std::io::stdout().lock());
+    if args.start.is_some() || args.end.is_some() {
+        let start = args.start.unwrap_or(0);
+        let end = args.end.unwrap_or_else(|| crate::format::body::original_size(entry));
+        out.write_all(&reader.read_range(entry, start, end)?)?;
+        out.flush()?;
+        return Ok(());
+    }
+
+    // チャンクごとに書くので、ファイルが大きくてもメモリはチャンク1つ分で済む
+    let result = reader.for_each_chunk(entry, |chunk| Ok(out.write_all(chunk)?));
+    out.flush()?;
+    // 壊れていたら全部書いた後で終了コードで知らせる
+    result
+}
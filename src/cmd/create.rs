@@ -1,9 +1,8 @@
-use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, ffi::OsString, io::{Read, Seek, Write}, path::PathBuf, sync::{Arc, Mutex}, thread};
+use std::{collections::{BTreeMap, HashMap, VecDeque}, ffi::OsString, io::{Read, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, Mutex}, thread};
 
-use prost::Message;
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
-use crate::proto::{self, CompressedMethod};
+use crate::{cmd::{fadvise, parse_zstd_level, Codec, Fadvise, DEFAULT_ZSTD_LEVEL}, error::MarError, format::body, proto::{self, CompressedMethod}, throttle::Throttle};
 
 use rayon::prelude::*;
 
@@ -16,83 +15,1036 @@ pub struct Args {
     #[arg(short, long)]
     output: PathBuf,
 
-    #[arg(short, long)]
+    /// Worker threads (default: number of logical CPUs)
+    #[arg(short, long, value_parser = parse_jobs, default_value_t = default_jobs())]
     jobs: usize,
 
     #[arg(long)]
     dedup: bool,
+
+    /// Skip SHA-256 entirely and keep only CRC32 (dedup needs SHA-256, so it can't be combined)
+    #[arg(long, conflicts_with = "dedup")]
+    no_integrity_hash: bool,
+
+    /// Write the index even if some files went missing or couldn't be read while archiving
+    #[arg(long)]
+    best_effort: bool,
+
+    /// Store every chunk as a standalone zstd frame, so a body is a valid multi-frame zstd stream
+    #[arg(long)]
+    zstd_frames: bool,
+
+    /// With --zstd-frames, put a zstd skippable frame holding the original size in front of every chunk
+    #[arg(long, requires = "zstd_frames")]
+    skippable_frame_headers: bool,
+
+    /// Compression method of the index file
+    #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+    index_codec: Codec,
+
+    /// Zstandard level for file bodies (negative levels are the fast modes)
+    #[arg(long, value_parser = parse_zstd_level, default_value_t = DEFAULT_ZSTD_LEVEL, allow_hyphen_values = true)]
+    level: i32,
+
+    /// Zstandard level for the index file (with --index-codec zstd)
+    #[arg(long, value_parser = parse_zstd_level, default_value_t = crate::format::index_file::DEFAULT_INDEX_ZSTD_LEVEL, allow_hyphen_values = true)]
+    index_level: i32,
+
+    /// Chunked files with fewer chunks than this are compressed serially on the worker thread
+    #[arg(long, default_value_t = 32)]
+    min_chunks_for_parallel: usize,
+
+    /// Store the body of files smaller than this many bytes inside the index instead of .dat
+    #[arg(long, default_value_t = 0)]
+    inline_under: usize,
+
+    /// After writing, decompress every entry and compare it against the source file
+    #[arg(long)]
+    audit: bool,
+
+    /// Start a new numbered .dat file whenever the current one would grow past this size (e.g. 2GiB)
+    #[arg(long, value_parser = parse_size)]
+    dat_split_size: Option<u64>,
+
+    /// Abort before compressing if the input contains more files than this
+    #[arg(long)]
+    max_entries: Option<usize>,
+
+    /// With --dedup, list each duplicate file and the file it was deduplicated against
+    #[arg(long, requires = "dedup")]
+    dedup_report: bool,
+
+    /// Keep files in directory walk order instead of sorting them by path
+    #[arg(long)]
+    preserve_order: bool,
+
+    /// If compressing a single file takes longer than this many seconds, store it with a faster codec instead
+    #[arg(long)]
+    compress_timeout: Option<u64>,
+
+    /// Sort the index by (original SHA-256, path) so files with identical content are adjacent
+    #[arg(long, conflicts_with = "preserve_order")]
+    group_by_hash: bool,
+
+    /// Print the N files with the largest compressed size at the end (0 = off)
+    #[arg(long, default_value_t = 0)]
+    report_largest: usize,
+
+    /// Files over 8MiB are chunked, but only files larger than this are compressed in parallel
+    #[arg(long, value_parser = parse_size, default_value = "64MiB")]
+    chunk_parallel_threshold_bytes: u64,
+
+    /// With --dedup, only files at least this large take part in dedup
+    #[arg(long, value_parser = parse_size, default_value_t = 0, requires = "dedup")]
+    dedup_min_size: u64,
+
+    /// Archive exactly the paths listed in this .mar.idx (read from --input) instead of walking --input
+    #[arg(long)]
+    paths_from_archive: Option<PathBuf>,
+
+    /// What to do when a path listed by --paths-from-archive doesn't exist in --input
+    #[arg(long, value_enum, default_value_t = OnMissing::Skip)]
+    on_missing: OnMissing,
+
+    /// Also write <output>.mar.catalog.idx, a copy of the index without any body locations or inline data
+    #[arg(long)]
+    index_only_output: bool,
+
+    /// Limit reading the input plus writing the .dat to about this many bytes per second, shared by all jobs
+    #[arg(long, value_parser = parse_throttle)]
+    throttle: Option<u64>,
+
+    /// Write a machine-readable summary of the run to this file (schema in README)
+    #[arg(long)]
+    stats_json: Option<PathBuf>,
+
+    /// Don't descend into directories on a different filesystem than --input (like tar --one-file-system)
+    #[arg(long)]
+    one_file_system: bool,
+
+    /// Debug: read the written .mar.idx back and check it matches what was meant to be written
+    #[arg(long)]
+    verify_index_roundtrip: bool,
+
+    /// Leave out files whose compressed size / original size is above this (e.g. 0.9), logging each one
+    #[arg(long)]
+    skip_if_ratio_above: Option<f64>,
+
+    /// Write bodies to the .dat in file order so the same input always gives a byte-identical archive, whatever --jobs is
+    #[arg(long, conflicts_with = "compress_timeout")]
+    reproducible: bool,
+
+    /// Only hold the .dat lock to reserve space, and write bodies to their reserved offsets in parallel
+    #[arg(long)]
+    concurrency_safe_append: bool,
+
+    /// Page cache hint for the input files and the .dat (Linux only): dontneed keeps a huge run from evicting everything else
+    #[arg(long, value_enum, default_value_t = Fadvise::Normal)]
+    fadvise: Fadvise,
+
+    /// Pack files smaller than this together into solidly compressed blobs (0 = off)
+    #[arg(long, value_parser = parse_size, default_value_t = 0)]
+    solid_under: u64,
+
+    /// Warn about every file larger than this
+    #[arg(long, value_parser = parse_size)]
+    warn_if_over: Option<u64>,
+
+    /// Leave the files flagged by --warn-if-over out of the archive
+    #[arg(long, requires = "warn_if_over")]
+    exclude_over: bool,
+
+    /// Debug: decompress every file's chunks right after compressing and check they give back the input
+    #[arg(long)]
+    self_check: bool,
+
+    /// Files each worker takes from the shared queue at once (default: scaled by file count and --jobs)
+    #[arg(long)]
+    batch: Option<usize>,
+
+    /// Also store inode, ctime and birth time (where the platform has them) for each file
+    #[arg(long)]
+    change_detection_metadata: bool,
+
+    /// With --dedup, always make the first file in processing order (by default, the first path) the stored copy
+    #[arg(long, requires = "dedup")]
+    deterministic_dedup: bool,
+
+    /// Print how long walking, reading, hashing, compressing and writing took (summed over all threads)
+    #[arg(long)]
+    profile: bool,
+
+    /// For chunked text files (by extension), end chunks right after a newline so edits shift fewer chunk boundaries
+    #[arg(long)]
+    text_align: bool,
+
+    /// Try to compress files with already-compressed extensions (.jpg, .png, .mp4, .zip, .gz, ...) too, instead of storing them as-is
+    #[arg(long)]
+    no_skip_compressed: bool,
+
+    /// Don't archive version control metadata (.git, .svn, .hg, .bzr, _darcs, CVS, RCS, SCCS), like tar --exclude-vcs
+    #[arg(long)]
+    exclude_vcs: bool,
+
+    /// Follow symlinks and archive what they point to, instead of storing the links themselves
+    #[arg(long)]
+    dereference: bool,
+
+    /// With --dereference, follow symlinks even when they point outside of --input (by default such links are skipped and reported)
+    #[arg(long)]
+    allow_external_symlinks: bool,
+
+    /// Report files whose compressed/original size is below this ratio (e.g. 0.01) at the end, to spot all-zero or placeholder files
+    #[arg(long)]
+    flag_ratio_below: Option<f64>,
+
+    /// Compress every file and chunk with this codec instead of picking between LZ4 and Zstandard
+    #[arg(long, value_enum, conflicts_with_all = ["best", "zstd_frames"])]
+    codec: Option<Codec>,
+
+    /// Train a zstd dictionary on the files up to 8MiB and compress each of them against it (the dictionary is stored in the index)
+    #[arg(long, conflicts_with_all = ["codec", "best", "zstd_frames"])]
+    dictionary: bool,
+
+    /// Compress every chunk with both LZ4 (HC) and Zstandard and keep the smaller one (slow)
+    #[arg(long, conflicts_with = "zstd_frames")]
+    best: bool,
+
+    /// Keep a chunk (or small file) compressed only if original/compressed size is above this; otherwise store it as-is
+    #[arg(long, value_parser = parse_min_ratio, default_value_t = 1.0)]
+    min_ratio: f64,
+
+    /// Size of the chunks files over 8MiB are split into (a power of two, e.g. 128K or 4M)
+    #[arg(long, value_parser = parse_chunk_size, default_value = "512KiB")]
+    chunk_size: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+enum OnMissing {
+    /// Abort without writing anything
+    Error,
+    /// Warn and archive the rest
+    Skip,
+}
+
+// 1024, 512K, 16MiB, 2G のような表記をバイト数にする
+fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (number, suffix) = s.split_at(digits);
+    let number: u64 = number.parse().map_err(|_| format!("invalid size: {}", s))?;
+    let multiplier: u64 = match suffix.trim().to_ascii_uppercase().as_str() {
+        "" | "B" => 1,
+        "K" | "KB" | "KIB" => 1 << 10,
+        "M" | "MB" | "MIB" => 1 << 20,
+        "G" | "GB" | "GIB" => 1 << 30,
+        "T" | "TB" | "TIB" => 1 << 40,
+        _ => return Err(format!("invalid size suffix: {}", suffix)),
+    };
+    number.checked_mul(multiplier).ok_or_else(|| format!("size too large: {}", s))
+}
+
+// 0 バイト/秒だと Throttle が 0 で割ってしまう。制限しないなら --throttle を付けない
+fn parse_throttle(s: &str) -> Result<u64, String> {
+    let rate = parse_size(s)?;
+    if rate == 0 {
+        return Err("must be at least 1 byte per second (omit --throttle for no limit)".to_string());
+    }
+    return Ok(rate);
+}
+
+fn default_jobs() -> usize {
+    return std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+}
+
+// 0 だとワーカーが1つも起動せず、空のアーカイブができてしまう
+fn parse_jobs(s: &str) -> Result<usize, String> {
+    let jobs: usize = s.parse().map_err(|_| format!("invalid number: {}", s))?;
+    if jobs == 0 {
+        return Err("must be at least 1".to_string());
+    }
+    Ok(jobs)
+}
+
+// 1.0 未満だと元より大きくなっても圧縮したものを使ってしまう
+fn parse_min_ratio(s: &str) -> Result<f64, String> {
+    let ratio: f64 = s.parse().map_err(|_| format!("invalid number: {}", s))?;
+    if !(ratio >= 1.0 && ratio.is_finite()) {
+        return Err(format!("must be at least 1.0: {}", s));
+    }
+    Ok(ratio)
+}
+
+// 小さすぎると index が膨れるので 4KiB 以上。ChunkInfo の長さは u32 なので、圧縮して少し膨らんでも収まる 1GiB まで
+fn parse_chunk_size(s: &str) -> Result<usize, String> {
+    let size = parse_size(s)?;
+    if !size.is_power_of_two() {
+        return Err(format!("must be a power of two: {}", s));
+    }
+    if !(4 << 10..=1 << 30).contains(&size) {
+        return Err(format!("must be between 4KiB and 1GiB: {}", s));
+    }
+    Ok(size as usize)
+}
+
+// sha2 crate は SHA-NI (x86) / SHA2 拡張 (aarch64) があれば実行時に自動で使う
+fn sha256_accelerated() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    return std::arch::is_x86_feature_detected!("sha");
+    #[cfg(target_arch = "aarch64")]
+    return std::arch::is_aarch64_feature_detected!("sha2");
+    #[allow(unreachable_code)]
+    return false;
 }
 
 #[derive(Debug)]
-struct FileInfo {
-    path: PathBuf,
-    size: u64,
+pub(crate) struct FileInfo {
+    pub(crate) path: PathBuf,
+    pub(crate) size: u64,
+    // 処理順 (index に書く順番を決めるのに使う)
+    order: usize,
+    // 辿らずにリンクとして保存する symlink
+    pub(crate) symlink: bool,
+}
+
+
+// symlink は辿らずに symlink として返す (create の既定と同じ)
+pub(crate) fn walk_dir(dir: &PathBuf) -> (Vec<FileInfo>, Vec<PathBuf>) {
+    return walk_dir_with(dir, &WalkOptions { device: None, exclude_vcs: false, root: None, dereference: false });
 }
 
+struct WalkOptions {
+    // Some の時は、それとは別のデバイス (= 別のファイルシステムのマウントポイント) には降りない
+    device: Option<u64>,
+    // VCS_DIRECTORIES には降りない
+    exclude_vcs: bool,
+    // Some の時は、リンク先がこの (canonicalize した) ディレクトリの外にある symlink を辿らない
+    root: Option<PathBuf>,
+    // false の時は symlink を辿らずに symlink として返す
+    dereference: bool,
+}
+
+// --exclude-vcs で飛ばすディレクトリ (tar --exclude-vcs のうちディレクトリのもの)
+const VCS_DIRECTORIES: &[&str] = &[".git", ".svn", ".hg", ".bzr", "_darcs", "CVS", "RCS", "SCCS"];
+
+fn walk_dir_with(dir: &PathBuf, options: &WalkOptions) -> (Vec<FileInfo>, Vec<PathBuf>) {
+    let mut ancestors = dir.canonicalize().into_iter().collect();
+    return walk_dir_inner(dir, options, &mut ancestors);
+}
 
-fn walk_dir(dir: &PathBuf) -> (Vec<FileInfo>, Vec<PathBuf>) {
+// ancestors は dir とその親の canonicalize したパス。symlink を辿って自分の親に戻ってくるループを避けるのに使う
+fn walk_dir_inner(dir: &PathBuf, options: &WalkOptions, ancestors: &mut Vec<PathBuf>) -> (Vec<FileInfo>, Vec<PathBuf>) {
     let mut files = Vec::new();
     let mut directories = Vec::new();
-    for entry in dir.read_dir().unwrap() {
-        let entry = entry.unwrap();
+    // 読めないディレクトリやファイルは報告して飛ばす
+    let read_dir = match dir.read_dir() {
+        Ok(read_dir) => read_dir,
+        Err(e) => {
+            println!("warning: skipping unreadable directory {}: {}", dir.display(), e);
+            return (files, directories);
+        },
+    };
+    for entry in read_dir {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                println!("warning: skipping an entry of {}: {}", dir.display(), e);
+                continue;
+            },
+        };
         let path = entry.path();
+        // submodule や worktree の .git はファイルなので、ディレクトリかどうかに関係なく飛ばす
+        if options.exclude_vcs && entry.file_name().to_str().is_some_and(|name| VCS_DIRECTORIES.contains(&name)) {
+            continue;
+        }
+        let is_symlink = match entry.file_type() {
+            Ok(file_type) => file_type.is_symlink(),
+            Err(e) => {
+                println!("warning: skipping {}: {}", path.display(), e);
+                continue;
+            },
+        };
+        if is_symlink && !options.dereference {
+            files.push(FileInfo { path, size: 0, order: 0, symlink: true });
+            continue;
+        }
+        if let Some(root) = &options.root {
+            if is_symlink {
+                // リンク切れはこれまで通り下で扱う
+                if let Ok(target) = path.canonicalize() {
+                    if !target.starts_with(root) {
+                        println!("warning: refusing symlink {} -> {} outside of input (pass --allow-external-symlinks to follow it)", path.display(), target.display());
+                        continue;
+                    }
+                }
+            }
+        }
         if path.is_dir() {
-            let (mut f, mut d) = walk_dir(&path);
+            if options.device.is_some_and(|device| device_of(&path).is_ok_and(|d| d != device)) {
+                println!("warning: skipping mount point {} (--one-file-system)", path.display());
+                continue;
+            }
+            let canonical = match path.canonicalize() {
+                Ok(canonical) => canonical,
+                Err(e) => {
+                    println!("warning: skipping {}: {}", path.display(), e);
+                    continue;
+                },
+            };
+            if ancestors.contains(&canonical) {
+                println!("warning: skipping {}, a symlink back to {} (would loop forever)", path.display(), canonical.display());
+                continue;
+            }
+            ancestors.push(canonical);
+            let (mut f, mut d) = walk_dir_inner(&path, options, ancestors);
+            ancestors.pop();
             directories.push(path);
             directories.append(&mut d);
             files.append(&mut f);
         } else {
-            files.push(FileInfo { path: entry.path(), size: entry.metadata().unwrap().len() });
+            // リンク切れもここで飛ばす
+            let metadata = match path.metadata() {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    println!("warning: skipping {}: {}", path.display(), e);
+                    continue;
+                },
+            };
+            if !metadata.is_file() {
+                // FIFO やソケット、デバイスファイルは読もうとすると止まったりするので飛ばす
+                println!("warning: skipping non-regular file {}", path.display());
+                continue;
+            }
+            files.push(FileInfo { path: entry.path(), size: metadata.len(), order: 0, symlink: false });
         }
     }
     return (files, directories);
 }
 
-const CHUNK_SIZE: usize = 512 * 1024;
+// --change-detection-metadata で保存する inode, ctime, 作成日時
+#[cfg(unix)]
+fn change_detection(metadata: &std::fs::Metadata) -> proto::ChangeDetection {
+    use std::os::unix::fs::MetadataExt;
+    return proto::ChangeDetection {
+        inode: metadata.ino(),
+        change_time: Some(prost_types::Timestamp { seconds: metadata.ctime(), nanos: metadata.ctime_nsec() as i32 }),
+        birth_time: metadata.created().ok().map(prost_types::Timestamp::from),
+    };
+}
+
+// inode と ctime は無い (Windows の file index は std では unstable)
+#[cfg(not(unix))]
+fn change_detection(metadata: &std::fs::Metadata) -> proto::ChangeDetection {
+    return proto::ChangeDetection {
+        inode: 0,
+        change_time: None,
+        birth_time: metadata.created().ok().map(prost_types::Timestamp::from),
+    };
+}
+
+// 権限のビット (st_mode & 07777)。Windows では 0
+#[cfg(unix)]
+fn file_mode(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    return metadata.permissions().mode() & 0o7777;
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &std::fs::Metadata) -> u32 {
+    return 0;
+}
+
+#[cfg(unix)]
+fn device_of(path: &PathBuf) -> std::io::Result<u64> {
+    use std::os::unix::fs::MetadataExt;
+    return Ok(path.metadata()?.dev());
+}
+
+// st_dev が取れないので、全部同じファイルシステムとして扱う
+#[cfg(not(unix))]
+fn device_of(_path: &PathBuf) -> std::io::Result<u64> {
+    return Ok(0);
+}
+
+// 入力の中にある .mar.idx を探す (アーカイブをアーカイブしようとしているかもしれない)
+fn find_archives(files: &[FileInfo]) -> Vec<&PathBuf> {
+    return files.iter()
+        .map(|f| &f.path)
+        .filter(|p| p.file_name().and_then(|n| n.to_str()).is_some_and(|n| n.ends_with(".mar.idx")))
+        .collect();
+}
+
+// solid blob 1つ分の大きさの上限 (丸ごと圧縮される大きさに収める)
+pub(crate) const SOLID_BLOB_SIZE: u64 = WHOLE_FILE_THRESHOLD as u64;
+
+// 処理順で隣り合っているファイルを SOLID_BLOB_SIZE までまとめる
+fn group_solid_files(files: Vec<FileInfo>) -> Vec<Vec<FileInfo>> {
+    let mut groups = Vec::new();
+    let mut group = Vec::new();
+    let mut group_size = 0;
+    for file in files {
+        if !group.is_empty() && group_size + file.size > SOLID_BLOB_SIZE {
+            groups.push(std::mem::take(&mut group));
+            group_size = 0;
+        }
+        group_size += file.size;
+        group.push(file);
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    return groups;
+}
+
+// 小さいファイルを繋げて1つの body として圧縮する。
+// 各 entry はその body 全体を指しつつ、展開後のどこからどこまでが自分かを solid_offset/solid_length で持つ
+fn compress_solid_blob(files: &[FileInfo], input: &Path, outdatfile: &Mutex<DatWriter>, options: CompressOptions, no_integrity_hash: bool, with_change_detection: bool, throttle: Option<&Throttle>) -> Result<Vec<(usize, proto::FileEntry)>, MarError> {
+    use sha2::Digest;
+
+    if files.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut blob = Vec::new();
+    let mut members = Vec::with_capacity(files.len());
+    for file in files {
+        let data = timed(&PROFILE.read, || std::fs::read(&file.path)).map_err(MarError::at(&file.path))?;
+        if let Some(throttle) = throttle {
+            throttle.consume(data.len());
+        }
+        let metadata = std::fs::metadata(&file.path).map_err(MarError::at(&file.path))?;
+        let modified_time = metadata.modified().map_err(MarError::at(&file.path))?;
+        let change_detection = with_change_detection.then(|| change_detection(&metadata));
+        let mode = file_mode(&metadata);
+
+        let (relative_path, raw_path) = archived_path(input, &file.path)?;
+
+        let original_sha256 = match no_integrity_hash {
+            true => Vec::new(),
+            false => timed(&PROFILE.hash, || sha2::Sha256::digest(&data).to_vec()),
+        };
+        members.push((file.order, relative_path, raw_path, blob.len() as u64, data.len() as u64, crc32fast::hash(&data), original_sha256, modified_time, change_detection, mode));
+        blob.extend_from_slice(&data);
+    }
+
+    let chunks = compress_file(&blob, options);
+    if options.self_check {
+        self_check_chunks(&blob, &chunks, options.dictionary.unwrap_or_default(), "solid blob");
+    }
+    let mut chunk_infos = Vec::<proto::ChunkInfo>::with_capacity(chunks.len());
+    let mut compressed = Vec::new();
+    for mut chunk in chunks {
+        if options.skippable_frame_headers {
+            add_skippable_frame_header(&mut chunk);
+        }
+        chunk_infos.push(proto::ChunkInfo {
+            compressed_length: chunk.compressed.len() as u32,
+            compressed_method: chunk.compressed_method as i32,
+            original_length: chunk.original_size as u32,
+            using_dictionary: chunk.using_dictionary,
+        });
+        compressed.append(&mut chunk.compressed);
+    }
+    println!("solid: {} files ({} chunks, {} -> {} bytes)", files.len(), chunk_infos.len(), blob.len(), compressed.len());
+
+    let chunks_crc32 = crc32fast::hash(&compressed);
+    let chunks_sha256 = match no_integrity_hash {
+        true => Vec::new(),
+        false => sha2::Sha256::digest(&compressed).to_vec(),
+    };
+    let (file_index, offset) = outdatfile.lock().unwrap().write_body(&compressed)?;
+
+    return Ok(members.into_iter().map(|(order, path, raw_path, solid_offset, solid_length, original_crc32, original_sha256, modified_time, change_detection, mode)| {
+        (order, proto::FileEntry {
+            info: Some(proto::FileInfo {
+                path,
+                chunks: chunk_infos.clone(),
+                chunks_crc32,
+                chunks_sha256: chunks_sha256.clone(),
+                original_crc32,
+                original_sha256,
+                modified_time: Some(prost_types::Timestamp::from(modified_time)),
+                priority: 0,
+                change_detection,
+                symlink_target: String::new(),
+                mode,
+                raw_path,
+                raw_symlink_target: Vec::new(),
+            }),
+            file_index,
+            body_offset: offset,
+            body_size: compressed.len() as u64,
+            inline_body: Vec::new(),
+            in_solid_blob: true,
+            solid_offset,
+            solid_length,
+        })
+    }).collect());
+}
+
+// symlink はリンク先だけを記録して、中身は空のファイルとして扱う
+fn symlink_entries(symlinks: &[FileInfo], input: &Path, no_integrity_hash: bool, with_change_detection: bool) -> Result<Vec<(usize, proto::FileEntry)>, MarError> {
+    use sha2::Digest;
+
+    let empty_sha256 = match no_integrity_hash {
+        true => Vec::new(),
+        false => sha2::Sha256::digest(b"").to_vec(),
+    };
+    return symlinks.iter().map(|file| {
+        let (relative_path, raw_path) = archived_path(input, &file.path)?;
+        let target = std::fs::read_link(&file.path).map_err(MarError::at(&file.path))?;
+        let (symlink_target, raw_symlink_target) = crate::format::encode_path(target.as_os_str());
+        let metadata = file.path.symlink_metadata().map_err(MarError::at(&file.path))?;
+        let modified_time = metadata.modified().map_err(MarError::at(&file.path))?;
+        println!("symlink {} -> {}", relative_path, target.display());
+        Ok((file.order, proto::FileEntry {
+            info: Some(proto::FileInfo {
+                path: relative_path,
+                chunks: Vec::new(),
+                chunks_crc32: 0,
+                chunks_sha256: empty_sha256.clone(),
+                original_crc32: 0,
+                original_sha256: empty_sha256.clone(),
+                modified_time: Some(prost_types::Timestamp::from(modified_time)),
+                priority: 0,
+                change_detection: with_change_detection.then(|| change_detection(&metadata)),
+                symlink_target,
+                mode: file_mode(&metadata),
+                raw_path,
+                raw_symlink_target,
+            }),
+            ..Default::default()
+        }))
+    }).collect();
+}
+
+// 既存のアーカイブに入っているのと同じパスを、同じ順番で input から集める
+// 見つからなかったパスの数も返す
+fn files_from_archive(input: &PathBuf, old_index: &PathBuf, on_missing: OnMissing) -> Result<(Vec<FileInfo>, usize), MarError> {
+    let old_index = crate::format::index_file::parse_index_file(&mut crate::format::open_shared(old_index.as_os_str())?)?;
+    let mut files = Vec::new();
+    let mut missing = 0;
+    for entry in old_index.entries {
+        let info = entry.info.unwrap();
+        let path = info.path;
+        // 古い index の `..` や絶対パスで input の外のファイルを読みに行かない
+        let full_path = input.join(crate::format::contained_relative_path(&path, &info.raw_path)?);
+        match full_path.metadata() {
+            Ok(metadata) if metadata.is_file() => files.push(FileInfo { path: full_path, size: metadata.len(), order: 0, symlink: false }),
+            _ => {
+                missing += 1;
+                match on_missing {
+                    OnMissing::Error => eprintln!("error: {} is in the old archive but missing from the input", path),
+                    OnMissing::Skip => println!("warning: {} is in the old archive but missing from the input", path),
+                }
+            },
+        }
+    }
+    // 出力を作り始める前に止める
+    if on_missing == OnMissing::Error && missing > 0 {
+        return Err(MarError::Failed { reason: format!("{} listed paths are missing (use --on-missing skip to archive the rest)", missing) });
+    }
+    return Ok((files, missing));
+}
+
+// spool から .dat にコピーする時のバッファの大きさ
+const COPY_BUFFER_SIZE: usize = 512 * 1024;
+// これ以下のファイルはチャンクに分けずに丸ごと圧縮する
+const WHOLE_FILE_THRESHOLD: usize = 8 * 1024 * 1024;
+// これ以下のファイルは LZ4 で圧縮する (--chunk-size とは関係なく、元の CHUNK_SIZE のまま)
+const LZ4_FILE_THRESHOLD: usize = 512 * 1024;
 
 struct Chunk {
     start: usize,
     original_size: usize,
     compressed: Vec<u8>,
     compressed_method: CompressedMethod,
-    // using_dictionary: bool,
+    // --dictionary の辞書を使って zstd で圧縮した
+    using_dictionary: bool,
+}
+
+#[derive(Clone, Copy)]
+struct CompressOptions {
+    // 全チャンクを zstd のフレームにする (body を連結するとそのまま zstd -d で読める)
+    zstd_frames: bool,
+    // 各チャンクの前に元のサイズを書いた skippable frame を置く
+    skippable_frame_headers: bool,
+    // 圧縮した直後に展開して元に戻るか確かめる
+    self_check: bool,
+    // チャンクの区切りを改行の直後にずらす (テキストファイルだけ)
+    text_align: bool,
+    // これ未満のチャンク数のファイルは rayon を使わずに圧縮する
+    min_chunks_for_parallel: usize,
+    // これ以下のサイズのファイルはチャンクに分けるが rayon は使わない
+    chunk_parallel_threshold: usize,
+    // --chunk-size
+    chunk_size: usize,
+    // --level
+    zstd_level: i32,
+    // --best
+    best: bool,
+    // --codec
+    codec: Option<CompressedMethod>,
+    // --dictionary で学習した辞書。CompressOptions は Copy で全スレッドに配るので、leak して 'static にしたものを持つ
+    dictionary: Option<&'static [u8]>,
+    // --min-ratio
+    min_ratio: f64,
+}
+
+// .dat への書き込み先。dat_split_size を超えそうになったら次の番号の .dat に切り替える
+struct DatWriter {
+    archive: OsString,
+    split_size: Option<u64>,
+    file_index: u32,
+    // 書いた .dat 全部 (file_index 番目が今書いているもの)。終わるまで排他ロックを持ち続けて、--audit ではこれを読む
+    files: Vec<Arc<std::fs::File>>,
+    // 今の .dat に予約済みのバイト数 (= 次の body の offset)
+    len: u64,
+    throttle: Option<Arc<Throttle>>,
+    fadvise: Fadvise,
 }
 
-static RAYON_LOCK: Mutex<()> = Mutex::new(());
+impl DatWriter {
+    fn new(archive: OsString, split_size: Option<u64>, throttle: Option<Arc<Throttle>>, fadvise: Fadvise) -> Result<Self, MarError> {
+        let file = Arc::new(Self::create_dat(&archive, 0, fadvise)?);
+        return Ok(DatWriter { archive, split_size, file_index: 0, files: vec![file], len: 0, throttle, fadvise });
+    }
+
+    fn create_dat(archive: &OsString, file_index: u32, advice: Fadvise) -> Result<std::fs::File, MarError> {
+        let path = crate::format::dat_file_path(archive, file_index);
+        println!("Output: {}", path.to_string_lossy());
+        let file = crate::format::create_exclusive(&path)?;
+        // Dontneed は書いた所ごとにやる
+        if advice == Fadvise::Sequential {
+            fadvise(&file, 0, 0, advice);
+        }
+        return Ok(file);
+    }
+
+    // size バイトの場所を確保して (file_index, offset, 書き込む .dat) を返す
+    fn reserve(&mut self, size: u64) -> Result<(u32, u64, Arc<std::fs::File>), MarError> {
+        if let Some(split_size) = self.split_size {
+            // 1つの body が複数の .dat に跨らないように、入り切らない時は丸ごと次の .dat に書く
+            if self.len > 0 && self.len + size > split_size {
+                self.files.push(Arc::new(Self::create_dat(&self.archive, self.file_index + 1, self.fadvise)?));
+                self.file_index += 1;
+                self.len = 0;
+            }
+        }
+        let offset = self.len;
+        self.len += size;
+        return Ok((self.file_index, offset, self.files[self.file_index as usize].clone()));
+    }
+
+    fn files(&self) -> Vec<Arc<std::fs::File>> {
+        return self.files.clone();
+    }
+
+    // body を書き込んで (file_index, offset) を返す
+    fn write_body(&mut self, body: &[u8]) -> Result<(u32, u64), MarError> {
+        let (file_index, offset, file) = self.reserve(body.len() as u64)?;
+        if let Some(throttle) = &self.throttle {
+            throttle.consume(body.len());
+        }
+        timed(&PROFILE.write_dat, || write_all_at(&file, body, offset)).map_err(dat_error(&self.archive, file_index))?;
+        if self.fadvise == Fadvise::Dontneed {
+            fadvise(&file, offset, body.len() as u64, Fadvise::Dontneed);
+        }
+        return Ok((file_index, offset));
+    }
+}
+
+// .dat への書き込みのエラーに、どの .dat かを付ける
+fn dat_error(archive: &OsString, file_index: u32) -> impl FnOnce(std::io::Error) -> MarError {
+    let path = crate::format::dat_file_path(archive, file_index).to_string_lossy().into_owned();
+    move |source| MarError::File { path, source }
+}
+
+// --concurrency-safe-append 用。場所の確保だけ lock を取って、書き込み自体は他のワーカーと並行して行う
+fn write_body_unlocked(dat: &Mutex<DatWriter>, body: &[u8]) -> Result<(u32, u64), MarError> {
+    let (file_index, offset, file, throttle, advice, archive) = {
+        let mut dat = dat.lock().unwrap();
+        let (file_index, offset, file) = dat.reserve(body.len() as u64)?;
+        (file_index, offset, file, dat.throttle.clone(), dat.fadvise, dat.archive.clone())
+    };
+    if let Some(throttle) = throttle {
+        throttle.consume(body.len());
+    }
+    timed(&PROFILE.write_dat, || write_all_at(&file, body, offset)).map_err(dat_error(&archive, file_index))?;
+    if advice == Fadvise::Dontneed {
+        fadvise(&file, offset, body.len() as u64, Fadvise::Dontneed);
+    }
+    return Ok((file_index, offset));
+}
+
+// spool に書いてある len バイトの body を .dat にコピーして (file_index, offset) を返す
+// --concurrency-safe-append なら場所を確保した後は lock を離して書く
+fn write_spooled_body(dat: &Mutex<DatWriter>, spool: &mut std::fs::File, len: u64, concurrency_safe_append: bool) -> Result<(u32, u64), MarError> {
+    use std::io::Seek;
+
+    let mut guard = Some(dat.lock().unwrap());
+    let (file_index, offset, file) = guard.as_mut().unwrap().reserve(len)?;
+    let (throttle, advice, archive) = {
+        let dat = guard.as_ref().unwrap();
+        (dat.throttle.clone(), dat.fadvise, dat.archive.clone())
+    };
+    if concurrency_safe_append {
+        guard = None;
+    }
+
+    spool.seek(std::io::SeekFrom::Start(0))?;
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut written = 0;
+    while written < len {
+        let n = (len - written).min(buf.len() as u64) as usize;
+        spool.read_exact(&mut buf[..n])?;
+        if let Some(throttle) = &throttle {
+            throttle.consume(n);
+        }
+        timed(&PROFILE.write_dat, || write_all_at(&file, &buf[..n], offset + written)).map_err(dat_error(&archive, file_index))?;
+        written += n as u64;
+    }
+    if advice == Fadvise::Dontneed {
+        fadvise(&file, offset, len, Fadvise::Dontneed);
+    }
+    drop(guard);
+    return Ok((file_index, offset));
+}
+
+#[cfg(unix)]
+fn write_all_at(file: &std::fs::File, buf: &[u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    return file.write_all_at(buf, offset);
+}
+
+#[cfg(windows)]
+fn write_all_at(file: &std::fs::File, mut buf: &[u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = file.seek_write(buf, offset)?;
+        if n == 0 {
+            return Err(std::io::ErrorKind::WriteZero.into());
+        }
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    return Ok(());
+}
+
+// --self-check: チャンクが隙間なく並んでいて、展開すると input のその範囲に戻ることを確かめる
+// 壊れていたら .dat に書く前にどのチャンクかが分かるように panic する
+fn self_check_chunks(input: &[u8], chunks: &[Chunk], dictionary: &[u8], path: &str) {
+    let mut expected_start = 0;
+    for (i, chunk) in chunks.iter().enumerate() {
+        assert!(chunk.start == expected_start, "self-check: {}: chunk {} starts at {}, expected {}", path, i, chunk.start, expected_start);
+        let end = chunk.start + chunk.original_size;
+        assert!(end <= input.len(), "self-check: {}: chunk {} ends at {}, past the input ({} bytes)", path, i, end, input.len());
+        self_check_chunk(&input[chunk.start..end], chunk, dictionary, i, path);
+        expected_start = end;
+    }
+    assert!(expected_start == input.len(), "self-check: {}: chunks cover {} of {} bytes", path, expected_start, input.len());
+}
+
+// src はそのチャンクの元のデータ
+fn self_check_chunk(src: &[u8], chunk: &Chunk, dictionary: &[u8], i: usize, path: &str) {
+    let chunk_info = proto::ChunkInfo {
+        compressed_length: chunk.compressed.len() as u32,
+        original_length: chunk.original_size as u32,
+        compressed_method: chunk.compressed_method as i32,
+        using_dictionary: chunk.using_dictionary,
+    };
+    let decoded = match body::decode_chunk(&chunk_info, &chunk.compressed, dictionary) {
+        Ok(decoded) => decoded,
+        Err(e) => panic!("self-check: {}: chunk {} ({:?}) failed to decode: {}", path, i, chunk.compressed_method, e),
+    };
+    assert!(decoded == src, "self-check: {}: chunk {} ({:?}, {}..{}) doesn't decode to the input", path, i, chunk.compressed_method, chunk.start, chunk.start + chunk.original_size);
+}
+
+// zstd の skippable frame (magic 0x184D2A50, little-endian) で、中身は元のサイズ (u32 little-endian)
+// zstd のデコーダーは読み飛ばすので、チャンクの一部として compressed_length に含めてしまってよい
+const SKIPPABLE_FRAME_MAGIC: u32 = 0x184D2A50;
+
+fn add_skippable_frame_header(chunk: &mut Chunk) {
+    assert!(chunk.compressed_method == CompressedMethod::Zstandard);
+    let mut framed = Vec::with_capacity(12 + chunk.compressed.len());
+    framed.extend_from_slice(&SKIPPABLE_FRAME_MAGIC.to_le_bytes());
+    framed.extend_from_slice(&4u32.to_le_bytes());
+    framed.extend_from_slice(&(chunk.original_size as u32).to_le_bytes());
+    framed.append(&mut chunk.compressed);
+    chunk.compressed = framed;
+}
+
+// --reproducible 用。ワーカーが workload から取った順番 (seq) 通りに書き込ませる
+// 圧縮は並列のままで、書き込みだけが順番待ちになる
+struct WriteSequencer {
+    next: Mutex<usize>,
+    cond: std::sync::Condvar,
+}
+
+impl WriteSequencer {
+    fn new() -> Self {
+        WriteSequencer { next: Mutex::new(0), cond: std::sync::Condvar::new() }
+    }
+
+    fn turn(self: &Arc<Self>, seq: usize) -> Turn {
+        Turn { sequencer: self.clone(), seq }
+    }
+}
+
+struct Turn {
+    sequencer: Arc<WriteSequencer>,
+    seq: usize,
+}
+
+impl Turn {
+    // 自分より前の seq が全部終わるまで待つ
+    fn wait(&self) {
+        let mut next = self.sequencer.next.lock().unwrap_or_else(|e| e.into_inner());
+        while *next != self.seq {
+            next = self.sequencer.cond.wait(next).unwrap_or_else(|e| e.into_inner());
+        }
+    }
+}
+
+impl Drop for Turn {
+    // 飛ばしたファイルや panic したファイルも含めて、必ず次に回す
+    fn drop(&mut self) {
+        self.wait();
+        *self.sequencer.next.lock().unwrap_or_else(|e| e.into_inner()) += 1;
+        self.sequencer.cond.notify_all();
+    }
+}
+
+// ワーカーが workload から取ってきて、まだ手を付けていないファイル
+// エラーや panic でワーカーが途中で抜けても、残った分の番を drop で次に回す (回さないと他のワーカーがその番を待ち続ける)
+struct Batch {
+    files: VecDeque<(usize, FileInfo)>,
+    sequencer: Option<Arc<WriteSequencer>>,
+}
+
+impl Drop for Batch {
+    fn drop(&mut self) {
+        if let Some(sequencer) = &self.sequencer {
+            for (seq, _) in self.files.drain(..) {
+                drop(sequencer.turn(seq));
+            }
+        }
+    }
+}
+
+// --profile 用の各段階の所要時間 (ナノ秒、全スレッドの合計)。無効でも数えるだけ数えておく
+struct Profile {
+    walk: AtomicU64,
+    read: AtomicU64,
+    hash: AtomicU64,
+    compress_zstd: AtomicU64,
+    compress_lz4: AtomicU64,
+    compress_brotli: AtomicU64,
+    write_dat: AtomicU64,
+    write_index: AtomicU64,
+}
 
-fn compress_file(input_data: &[u8]) -> Vec<Chunk> {
+static PROFILE: Profile = Profile {
+    walk: AtomicU64::new(0),
+    read: AtomicU64::new(0),
+    hash: AtomicU64::new(0),
+    compress_zstd: AtomicU64::new(0),
+    compress_lz4: AtomicU64::new(0),
+    compress_brotli: AtomicU64::new(0),
+    write_dat: AtomicU64::new(0),
+    write_index: AtomicU64::new(0),
+};
+
+fn timed<T>(counter: &AtomicU64, f: impl FnOnce() -> T) -> T {
+    let start = std::time::Instant::now();
+    let result = f();
+    counter.fetch_add(start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    return result;
+}
+
+#[derive(serde::Serialize)]
+struct ProfileStats {
+    walk_ms: u64,
+    read_ms: u64,
+    hash_ms: u64,
+    compress_zstd_ms: u64,
+    compress_lz4_ms: u64,
+    compress_brotli_ms: u64,
+    write_dat_ms: u64,
+    write_index_ms: u64,
+}
+
+impl Profile {
+    fn stats(&self) -> ProfileStats {
+        let ms = |counter: &AtomicU64| counter.load(Ordering::Relaxed) / 1_000_000;
+        return ProfileStats {
+            walk_ms: ms(&self.walk),
+            read_ms: ms(&self.read),
+            hash_ms: ms(&self.hash),
+            compress_zstd_ms: ms(&self.compress_zstd),
+            compress_lz4_ms: ms(&self.compress_lz4),
+            compress_brotli_ms: ms(&self.compress_brotli),
+            write_dat_ms: ms(&self.write_dat),
+            write_index_ms: ms(&self.write_index),
+        };
+    }
+}
+
+// 圧縮したものを使うかどうかの唯一の基準 (丸ごと圧縮するファイルでもチャンクでも同じ)
+// 元のサイズ / 圧縮後のサイズが --min-ratio を超えた時だけ使う。既定の 1.0 なら少しでも縮めば使う
+fn worth_compressing(original: usize, compressed: usize, options: CompressOptions) -> bool {
+    return original as f64 > compressed as f64 * options.min_ratio;
+}
+
+fn compress_file(input_data: &[u8], options: CompressOptions) -> Vec<Chunk> {
+    return compress_file_until(input_data, options, &AtomicBool::new(false));
+}
+
+// cancel が立ったら残りのチャンクを圧縮せずに返す (その時の結果は途中までしか無いので捨てること)
+// チャンクに分けない大きさ (WHOLE_FILE_THRESHOLD 以下) のファイルは最後まで圧縮する
+fn compress_file_until(input_data: &[u8], options: CompressOptions, cancel: &AtomicBool) -> Vec<Chunk> {
+    // --best の時はチャンクに分けない大きさのファイルも丸ごと両方で試す
+    if options.best && input_data.len() <= WHOLE_FILE_THRESHOLD {
+        return vec![compress_best(0, input_data, options)];
+    }
+    if let Some(method) = options.codec.filter(|_| input_data.len() <= WHOLE_FILE_THRESHOLD) {
+        return vec![compress_with_codec(0, input_data, method, options)];
+    }
+    if let Some(dictionary) = options.dictionary.filter(|_| input_data.len() <= WHOLE_FILE_THRESHOLD) {
+        return vec![compress_with_dictionary(input_data, dictionary, options)];
+    }
     // 小さいファイルはサクッと読みたさそうなので適当にlz4で圧縮する
-    if input_data.len() <= CHUNK_SIZE {
-        let compressed_with_lz4 = lz4::block::compress(input_data, Some(lz4::block::CompressionMode::HIGHCOMPRESSION(12)), false).unwrap();
-        if input_data.len() > compressed_with_lz4.len() {
+    if input_data.len() <= LZ4_FILE_THRESHOLD && !options.zstd_frames {
+        let compressed_with_lz4 = timed(&PROFILE.compress_lz4, || lz4::block::compress(input_data, Some(lz4::block::CompressionMode::HIGHCOMPRESSION(12)), false)).unwrap();
+        if worth_compressing(input_data.len(), compressed_with_lz4.len(), options) {
             return vec![Chunk {
                 start: 0,
                 original_size: input_data.len(),
                 compressed: compressed_with_lz4,
                 compressed_method: CompressedMethod::Lz4,
-                // using_dictionary: false,
+                using_dictionary: false,
             }];
         }
     }
     // 入力サイズが 8MB 以下の時はチャンク毎圧縮をしない (十分に小さいためシーク時の遅さを気にする必要がない…ことにする)
-    if input_data.len() <= 8 * 1024 * 1024 {
+    if input_data.len() <= WHOLE_FILE_THRESHOLD {
         // input_data を Zstandard で圧縮したもの
-        let compressed_with_zstd = {
+        let compressed_with_zstd = timed(&PROFILE.compress_zstd, || {
             let mut buf = Vec::<u8>::with_capacity(input_data.len() * 2);
-            let mut encoder = zstd::Encoder::new(&mut buf, 22).unwrap();
+            let mut encoder = zstd::Encoder::new(&mut buf, options.zstd_level).unwrap();
             encoder.write_all(&input_data).unwrap();
             encoder.finish().unwrap();
             buf
-        };
+        });
 
         // 圧縮成功したら圧縮したものを返す、そうでなかったらパススルー
-        if input_data.len() > compressed_with_zstd.len() {
+        if worth_compressing(input_data.len(), compressed_with_zstd.len(), options) || options.zstd_frames {
             return vec![Chunk {
                 start: 0,
                 original_size: input_data.len(),
                 compressed: compressed_with_zstd,
                 compressed_method: CompressedMethod::Zstandard,
-                // using_dictionary: false,
+                using_dictionary: false,
             }];
         } else {
             return vec![Chunk {
@@ -100,92 +1052,468 @@ fn compress_file(input_data: &[u8]) -> Vec<Chunk> {
                 original_size: input_data.len(),
                 compressed: input_data.to_vec(),
                 compressed_method: CompressedMethod::Passthrough,
-                // using_dictionary: false,
+                using_dictionary: false,
             }];
         }
     }
 
-    // 入力データを CHUNK_SIZE ずつに分割して圧縮する
-    let mut chunks = Vec::<Chunk>::new();
+    // 入力データを chunk_size ずつに分割して圧縮する
     let mut sources = Vec::<(usize, &[u8])>::new();
-    for i in (0..input_data.len()).step_by(CHUNK_SIZE) {
-        // 範囲を取得
-        let end = (i + CHUNK_SIZE).min(input_data.len());
-        let src = &input_data[i..end];
-        sources.push((i, src));
+    for (start, end) in chunk_ranges(input_data, options.chunk_size, options.text_align) {
+        sources.push((start, &input_data[start..end]));
     };
 
-    let lock = RAYON_LOCK.lock();
+    // チャンク数が少ない (or ファイルがそこまで大きくない) 時はスレッドプールを使うまでもないので今のスレッドで圧縮する
+    if sources.len() < options.min_chunks_for_parallel || input_data.len() <= options.chunk_parallel_threshold {
+        return sources
+            .iter()
+            .take_while(|_| !cancel.load(Ordering::Relaxed))
+            .map(|(i, src)| compress_chunk(*i, src, options))
+            .collect();
+    }
 
-    println!("start");
-    let chunks = sources
+    // 全ワーカーで rayon のグローバルなプールを共有する。大きなファイルが同時に来ても、チャンクは同じプールのスレッド数までしか並列に圧縮されない
+    // (ワーカースレッドは par_iter が終わるまで待っているだけなので CPU は取り合わない)
+    return sources
         .par_iter()
-        .map(|(i, src)| {
-            let should_use_lz4 = *i == 0;
-            let compressed = match should_use_lz4 {
-                true => lz4::block::compress(src, Some(lz4::block::CompressionMode::HIGHCOMPRESSION(12)), false).unwrap(),
-                false => {
-                    let mut buf = Vec::<u8>::with_capacity(CHUNK_SIZE * 2);
-                    let mut encoder = zstd::Encoder::new(&mut buf, 22).unwrap();
-                    encoder.write_all(src).unwrap();
-                    encoder.finish().unwrap();
-                    buf
-                }
-            };
-    
-            let is_compressed = compressed.len() < (src.len() / 4 * 3);
-    
-            if is_compressed {
-                // 圧縮できた
-                Chunk {
-                    start: *i,
-                    original_size: src.len(),
-                    compressed,
-                    compressed_method: match should_use_lz4 {
-                        true => CompressedMethod::Lz4,
-                        false => CompressedMethod::Zstandard
-                    },
-                    // using_dictionary: false,
+        .filter(|_| !cancel.load(Ordering::Relaxed))
+        .map(|(i, src)| compress_chunk(*i, src, options))
+        .collect();
+}
+
+// 別スレッドで圧縮して、時間内に終わらなかったら None を返す
+// 終わらなかった圧縮は次のチャンクの区切りで止まる。チャンクに分けないファイルだと最後まで裏で走るが、それは 8MiB 分の圧縮までで済む
+fn compress_file_with_timeout(input_data: Arc<Vec<u8>>, options: CompressOptions, timeout: std::time::Duration) -> Option<Vec<Chunk>> {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let thread_cancel = cancel.clone();
+    thread::spawn(move || {
+        // 止められた時は受け取る側がもう居ないので、途中までの結果が使われることはない
+        _ = tx.send(compress_file_until(&input_data, options, &thread_cancel));
+    });
+    let chunks = rx.recv_timeout(timeout).ok();
+    if chunks.is_none() {
+        cancel.store(true, Ordering::Relaxed);
+    }
+    return chunks;
+}
+
+// 圧縮し終わった body。大きなファイルはメモリに載せずに spool ファイルに書いてある
+enum CompressedBody {
+    Memory(Vec<u8>),
+    Spooled { len: u64, crc32: u32, sha256: Vec<u8> },
+}
+
+impl CompressedBody {
+    fn len(&self) -> u64 {
+        match self {
+            CompressedBody::Memory(compressed) => compressed.len() as u64,
+            CompressedBody::Spooled { len, .. } => *len,
+        }
+    }
+}
+
+// spool_files で圧縮したファイルの中身
+struct StreamedFile {
+    chunk_infos: Vec<proto::ChunkInfo>,
+    body: CompressedBody,
+    original_size: u64,
+    original_crc32: u32,
+    original_sha256: Vec<u8>,
+}
+
+// WHOLE_FILE_THRESHOLD より大きいファイルを chunk_size ずつ読みながら圧縮して spool に書く
+// 読んだデータも圧縮したデータも一度に持つのはチャンク数個分 (rayon を使う時はプールのスレッド数分) だけ
+fn compress_streaming(reader: &mut impl Read, size: u64, spool: &mut std::fs::File, options: CompressOptions, no_integrity_hash: bool, throttle: Option<&Throttle>, path: &str) -> Result<StreamedFile, MarError> {
+    use sha2::Digest;
+    use std::io::Seek;
+
+    // compress_file と同じ条件で rayon を使う
+    let chunk_size = options.chunk_size;
+    let chunk_count = size as usize / chunk_size + usize::from(size as usize % chunk_size != 0);
+    let parallel = chunk_count >= options.min_chunks_for_parallel && size as usize > options.chunk_parallel_threshold;
+    let batch_size = if parallel { rayon::current_num_threads().max(1) } else { 1 };
+
+    spool.set_len(0)?;
+    spool.rewind()?;
+    let mut spool_writer = std::io::BufWriter::new(spool);
+
+    let mut original_crc32 = crc32fast::Hasher::new();
+    let mut original_sha256 = sha2::Sha256::new();
+    let mut chunks_crc32 = crc32fast::Hasher::new();
+    let mut chunks_sha256 = sha2::Sha256::new();
+    let mut chunk_infos = Vec::new();
+    let mut len = 0;
+
+    // まだチャンクにしていない読んだデータ。text_align で後ろに回した分もここに残る
+    let mut pending = Vec::<u8>::with_capacity(chunk_size + 1);
+    let mut start = 0;
+    let mut eof = false;
+    while !eof || !pending.is_empty() {
+        let mut windows = Vec::with_capacity(batch_size);
+        while windows.len() < batch_size && (!eof || !pending.is_empty()) {
+            // 後ろにまだデータがあるか分かるように、1バイト余分に読んでおく
+            while !eof && pending.len() <= chunk_size {
+                let want = chunk_size + 1 - pending.len();
+                let old_len = pending.len();
+                pending.resize(old_len + want, 0);
+                let n = timed(&PROFILE.read, || reader.read(&mut pending[old_len..])).map_err(MarError::at(Path::new(path)))?;
+                pending.truncate(old_len + n);
+                if n == 0 {
+                    eof = true;
+                    break;
                 }
-            } else {
-                // 圧縮できなかった
-                Chunk {
-                    start: *i,
-                    original_size: src.len(),
-                    compressed: src.to_vec(),
-                    compressed_method: CompressedMethod::Passthrough,
-                    // using_dictionary: false,
+                if let Some(throttle) = throttle {
+                    throttle.consume(n);
                 }
+                timed(&PROFILE.hash, || {
+                    original_crc32.update(&pending[old_len..]);
+                    if !no_integrity_hash {
+                        original_sha256.update(&pending[old_len..]);
+                    }
+                });
             }
-        })
-        .collect();
+            if pending.is_empty() {
+                break;
+            }
+            // chunk_ranges と同じ区切り方
+            let (end, _) = chunk_ranges(&pending, chunk_size, options.text_align)[0];
+            let rest = pending.split_off(end);
+            let window = std::mem::replace(&mut pending, rest);
+            pending.reserve(chunk_size + 1);
+            windows.push((start, window));
+            start += end;
+        }
 
-    _ = lock;
-    println!("end");
+        let chunks: Vec<Chunk> = if parallel {
+            windows.par_iter().map(|(start, src)| compress_chunk(*start, src, options)).collect()
+        } else {
+            windows.iter().map(|(start, src)| compress_chunk(*start, src, options)).collect()
+        };
+        for ((_, src), mut chunk) in windows.iter().zip(chunks) {
+            if options.self_check {
+                self_check_chunk(src, &chunk, options.dictionary.unwrap_or_default(), chunk_infos.len(), path);
+            }
+            if options.skippable_frame_headers {
+                add_skippable_frame_header(&mut chunk);
+            }
+            chunk_infos.push(proto::ChunkInfo {
+                compressed_length: chunk.compressed.len() as u32,
+                compressed_method: chunk.compressed_method as i32,
+                original_length: chunk.original_size as u32,
+                using_dictionary: chunk.using_dictionary,
+            });
+            chunks_crc32.update(&chunk.compressed);
+            if !no_integrity_hash {
+                chunks_sha256.update(&chunk.compressed);
+            }
+            spool_writer.write_all(&chunk.compressed)?;
+            len += chunk.compressed.len() as u64;
+        }
+    }
+    spool_writer.flush()?;
+
+    return Ok(StreamedFile {
+        chunk_infos,
+        body: CompressedBody::Spooled {
+            len,
+            crc32: chunks_crc32.finalize(),
+            sha256: if no_integrity_hash { Vec::new() } else { chunks_sha256.finalize().to_vec() },
+        },
+        original_size: start as u64,
+        original_crc32: original_crc32.finalize(),
+        original_sha256: if no_integrity_hash { Vec::new() } else { original_sha256.finalize().to_vec() },
+    });
+}
+
+// --text-align で改行を探す範囲 (チャンクの末尾からこれだけ戻る)
+const TEXT_ALIGN_WINDOW: usize = 64 * 1024;
+
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "log", "md", "csv", "tsv", "json", "xml", "html", "css", "js", "ts",
+    "rs", "go", "py", "c", "h", "cpp", "hpp", "java", "sh", "yaml", "yml", "toml", "ini", "sql",
+];
+
+fn is_text_path(path: &std::path::Path) -> bool {
+    return path.extension().and_then(|e| e.to_str()).is_some_and(|e| TEXT_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()));
+}
+
+// 中身が既に圧縮されていて、圧縮しても時間がかかるだけでほぼ縮まない拡張子 (--no-skip-compressed でなければそのまま入れる)
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "avif", "heic", "mp3", "m4a", "aac", "ogg", "opus", "flac",
+    "mp4", "m4v", "mov", "mkv", "webm", "zip", "gz", "tgz", "bz2", "xz", "zst", "lz4", "br", "7z", "rar",
+    "jar", "apk", "woff2",
+];
+
+fn is_compressed_path(path: &std::path::Path) -> bool {
+    return path.extension().and_then(|e| e.to_str()).is_some_and(|e| COMPRESSED_EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()));
+}
+
+// chunk_size ずつに区切った (start, end) を返す
+// text_align の時は、各チャンクの末尾を TEXT_ALIGN_WINDOW 以内で一番後ろにある改行の直後にずらす (無ければそのまま)
+fn chunk_ranges(input: &[u8], chunk_size: usize, text_align: bool) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    let mut start = 0;
+    while start < input.len() {
+        let mut end = (start + chunk_size).min(input.len());
+        if text_align && end < input.len() {
+            let window_start = end.saturating_sub(TEXT_ALIGN_WINDOW).max(start + 1);
+            if let Some(newline) = input[window_start..end].iter().rposition(|&b| b == b'\n') {
+                end = window_start + newline + 1;
+            }
+        }
+        ranges.push((start, end));
+        start = end;
+    }
+    return ranges;
+}
+
+// 圧縮に時間がかかりすぎた時用の速い圧縮 (lz4 の速いモード、zstd_frames なら zstd の level 1)
+fn compress_file_fast(input_data: &[u8], options: CompressOptions) -> Vec<Chunk> {
+    let mut chunks = Vec::new();
+    for i in (0..input_data.len().max(1)).step_by(options.chunk_size) {
+        let src = &input_data[i..(i + options.chunk_size).min(input_data.len())];
+        let (compressed, method) = match options.zstd_frames {
+            true => (timed(&PROFILE.compress_zstd, || zstd::encode_all(src, 1)).unwrap(), CompressedMethod::Zstandard),
+            false => (timed(&PROFILE.compress_lz4, || lz4::block::compress(src, Some(lz4::block::CompressionMode::DEFAULT), false)).unwrap(), CompressedMethod::Lz4),
+        };
+        if worth_compressing(src.len(), compressed.len(), options) || options.zstd_frames {
+            chunks.push(Chunk { start: i, original_size: src.len(), compressed, compressed_method: method, using_dictionary: false });
+        } else {
+            chunks.push(Chunk { start: i, original_size: src.len(), compressed: src.to_vec(), compressed_method: CompressedMethod::Passthrough, using_dictionary: false });
+        }
+    }
     return chunks;
 }
 
+fn compress_chunk(start: usize, src: &[u8], options: CompressOptions) -> Chunk {
+    if options.best {
+        return compress_best(start, src, options);
+    }
+    if let Some(method) = options.codec {
+        return compress_with_codec(start, src, method, options);
+    }
+    let should_use_lz4 = start == 0 && !options.zstd_frames;
+    let compressed = match should_use_lz4 {
+        true => timed(&PROFILE.compress_lz4, || lz4::block::compress(src, Some(lz4::block::CompressionMode::HIGHCOMPRESSION(12)), false)).unwrap(),
+        false => timed(&PROFILE.compress_zstd, || {
+            let mut buf = Vec::<u8>::with_capacity(src.len() * 2);
+            let mut encoder = zstd::Encoder::new(&mut buf, options.zstd_level).unwrap();
+            encoder.write_all(src).unwrap();
+            encoder.finish().unwrap();
+            buf
+        }),
+    };
+
+    let is_compressed = worth_compressing(src.len(), compressed.len(), options) || options.zstd_frames;
+
+    if is_compressed {
+        // 圧縮できた
+        Chunk {
+            start,
+            original_size: src.len(),
+            compressed,
+            compressed_method: match should_use_lz4 {
+                true => CompressedMethod::Lz4,
+                false => CompressedMethod::Zstandard
+            },
+            using_dictionary: false,
+        }
+    } else {
+        // 圧縮できなかった
+        Chunk {
+            start,
+            original_size: src.len(),
+            compressed: src.to_vec(),
+            compressed_method: CompressedMethod::Passthrough,
+            using_dictionary: false,
+        }
+    }
+}
+
+// --best: LZ4 (HC) と Zstandard の両方で圧縮して小さい方を使う。それも --min-ratio に届かなければそのまま入れる
+fn compress_best(start: usize, src: &[u8], options: CompressOptions) -> Chunk {
+    let compressed_with_lz4 = timed(&PROFILE.compress_lz4, || lz4::block::compress(src, Some(lz4::block::CompressionMode::HIGHCOMPRESSION(12)), false)).unwrap();
+    let compressed_with_zstd = timed(&PROFILE.compress_zstd, || {
+        let mut buf = Vec::<u8>::with_capacity(src.len() * 2);
+        let mut encoder = zstd::Encoder::new(&mut buf, options.zstd_level).unwrap();
+        encoder.write_all(src).unwrap();
+        encoder.finish().unwrap();
+        buf
+    });
+
+    let (compressed, compressed_method) = match compressed_with_lz4.len() < compressed_with_zstd.len() {
+        true => (compressed_with_lz4, CompressedMethod::Lz4),
+        false => (compressed_with_zstd, CompressedMethod::Zstandard),
+    };
+    if worth_compressing(src.len(), compressed.len(), options) {
+        Chunk { start, original_size: src.len(), compressed, compressed_method, using_dictionary: false }
+    } else {
+        Chunk { start, original_size: src.len(), compressed: src.to_vec(), compressed_method: CompressedMethod::Passthrough, using_dictionary: false }
+    }
+}
+
+// --codec: 全部のファイルとチャンクを同じ方式で圧縮する。--min-ratio に届かなければそのまま入れる
+fn compress_with_codec(start: usize, src: &[u8], method: CompressedMethod, options: CompressOptions) -> Chunk {
+    let compressed = match method {
+        CompressedMethod::Zstandard => timed(&PROFILE.compress_zstd, || {
+            let mut buf = Vec::<u8>::with_capacity(src.len() * 2);
+            let mut encoder = zstd::Encoder::new(&mut buf, options.zstd_level).unwrap();
+            encoder.write_all(src).unwrap();
+            encoder.finish().unwrap();
+            buf
+        }),
+        CompressedMethod::Lz4 => timed(&PROFILE.compress_lz4, || lz4::block::compress(src, Some(lz4::block::CompressionMode::HIGHCOMPRESSION(12)), false)).unwrap(),
+        CompressedMethod::Brotli => timed(&PROFILE.compress_brotli, || body::encode_brotli(src, 11)).unwrap(),
+        CompressedMethod::Passthrough => src.to_vec(),
+    };
+    if method != CompressedMethod::Passthrough && worth_compressing(src.len(), compressed.len(), options) {
+        Chunk { start, original_size: src.len(), compressed, compressed_method: method, using_dictionary: false }
+    } else {
+        Chunk { start, original_size: src.len(), compressed: src.to_vec(), compressed_method: CompressedMethod::Passthrough, using_dictionary: false }
+    }
+}
+
+// --dictionary: 丸ごと圧縮する大きさのファイルは、学習した辞書を使って zstd で圧縮する
+fn compress_with_dictionary(src: &[u8], dictionary: &[u8], options: CompressOptions) -> Chunk {
+    let compressed = timed(&PROFILE.compress_zstd, || {
+        let mut buf = Vec::<u8>::with_capacity(src.len());
+        let mut encoder = zstd::Encoder::with_dictionary(&mut buf, options.zstd_level, dictionary).unwrap();
+        encoder.write_all(src).unwrap();
+        encoder.finish().unwrap();
+        buf
+    });
+    if worth_compressing(src.len(), compressed.len(), options) {
+        Chunk { start: 0, original_size: src.len(), compressed, compressed_method: CompressedMethod::Zstandard, using_dictionary: true }
+    } else {
+        Chunk { start: 0, original_size: src.len(), compressed: src.to_vec(), compressed_method: CompressedMethod::Passthrough, using_dictionary: false }
+    }
+}
+
+// --dictionary で作る辞書の大きさ (zstd --train の既定と同じくらい)
+const DICTIONARY_SIZE: usize = 112 * 1024;
+// 学習には各ファイルの先頭のこれだけを使う
+const DICTIONARY_SAMPLE_SIZE: u64 = 128 * 1024;
+// サンプルの合計がこれを超える時は、ファイルを等間隔に間引く
+const DICTIONARY_SAMPLES_TOTAL: u64 = 100 * DICTIONARY_SIZE as u64;
+
+// 丸ごと圧縮する大きさのファイルから zstd の辞書を学習する。学習できなければ (ファイルが少なすぎるなど) 辞書なしで圧縮する
+fn train_dictionary(files: &[FileInfo], skip_compressed: bool) -> Option<Vec<u8>> {
+    let candidates = files.iter().filter(|file| {
+        file.size > 0 && file.size <= WHOLE_FILE_THRESHOLD as u64 && !(skip_compressed && is_compressed_path(&file.path))
+    }).collect::<Vec<_>>();
+    let total: u64 = candidates.iter().map(|file| file.size.min(DICTIONARY_SAMPLE_SIZE)).sum();
+    let step = (total / DICTIONARY_SAMPLES_TOTAL + 1) as usize;
+
+    let mut samples = Vec::new();
+    for file in candidates.iter().step_by(step) {
+        let mut sample = Vec::new();
+        // 読めないファイルは後でワーカーが報告するので、ここでは飛ばすだけ
+        if std::fs::File::open(&file.path).and_then(|f| f.take(DICTIONARY_SAMPLE_SIZE).read_to_end(&mut sample)).is_ok() && !sample.is_empty() {
+            samples.push(sample);
+        }
+    }
+    match zstd::dict::from_samples(&samples, DICTIONARY_SIZE) {
+        Ok(dictionary) => {
+            println!("dictionary: {} bytes, trained on {} files", dictionary.len(), samples.len());
+            return Some(dictionary);
+        },
+        Err(e) => {
+            println!("warning: couldn't train a dictionary from {} files ({}), compressing without one", samples.len(), e);
+            return None;
+        },
+    }
+}
 
-pub fn main(args: Args) {
-    let (mut files, directories) = walk_dir(&args.input);
-    files.sort_by_key(|f| f.path.to_str().unwrap().to_string());
+pub fn main(args: Args) -> Result<(), MarError> {
+    let started = std::time::Instant::now();
+    let mut missing_paths = 0;
+    let walk_start = std::time::Instant::now();
+    let (mut files, directories) = match &args.paths_from_archive {
+        Some(old_index) => {
+            let (files, missing) = files_from_archive(&args.input, old_index, args.on_missing)?;
+            missing_paths = missing;
+            (files, Vec::new())
+        },
+        None => walk_dir_with(&args.input, &WalkOptions {
+            device: match args.one_file_system {
+                true => Some(device_of(&args.input).map_err(MarError::at(&args.input))?),
+                false => None,
+            },
+            exclude_vcs: args.exclude_vcs,
+            root: match args.allow_external_symlinks {
+                true => None,
+                false => Some(args.input.canonicalize().map_err(MarError::at(&args.input))?),
+            },
+            dereference: args.dereference,
+        }),
+    };
+    PROFILE.walk.fetch_add(walk_start.elapsed().as_nanos() as u64, Ordering::Relaxed);
+    // うっかり混ざった巨大なファイル (コアダンプとか) で archive が膨れないようにする
+    if let Some(limit) = args.warn_if_over {
+        let mut oversized_files = 0;
+        let mut oversized_bytes = 0;
+        files.retain(|file| {
+            if file.size <= limit {
+                return true;
+            }
+            eprintln!("warning: {} is {} bytes, over --warn-if-over {}{}", file.path.display(), file.size, limit, if args.exclude_over { ", excluding" } else { "" });
+            oversized_files += 1;
+            oversized_bytes += file.size;
+            return !args.exclude_over;
+        });
+        if oversized_files > 0 {
+            eprintln!("warning: {} files ({} bytes) are over --warn-if-over{}", oversized_files, oversized_bytes, if args.exclude_over { " and were excluded" } else { "" });
+        }
+    }
+
+    for archive in find_archives(&files) {
+        println!("warning: {} looks like a MAR archive, its .mar.idx/.mar.dat will be stored as opaque files", archive.display());
+    }
+    // --paths-from-archive の時は元のアーカイブの順番で処理する
+    if !args.preserve_order && args.paths_from_archive.is_none() {
+        files.sort_by(|a, b| a.path.as_os_str().cmp(b.path.as_os_str()));
+    }
+    for (i, file) in files.iter_mut().enumerate() {
+        file.order = i;
+    }
     // println!("Files: {:#?}", files);
 
     let files_count: usize = files.len();
 
-    let workload = Arc::new(Mutex::new(VecDeque::from(files)));
+    if let Some(max_entries) = args.max_entries {
+        if files_count > max_entries {
+            return Err(MarError::Failed { reason: format!("found {} files in {}, which exceeds --max-entries {}", files_count, args.input.display(), max_entries) });
+        }
+    }
+
+    if !args.no_integrity_hash {
+        println!("SHA-256 hardware acceleration: {}", if sha256_accelerated() { "available" } else { "not available" });
+    }
+
+    // symlink は読む中身が無いので、ワーカーには渡さずに後で entry を作る
+    let (symlinks, files): (Vec<_>, Vec<_>) = files.into_iter().partition(|f| f.symlink);
+    // --solid-under 未満の小さいファイルはワーカーには渡さずに、後でまとめて1つの塊として圧縮する
+    let (solid_files, files): (Vec<_>, Vec<_>) = files.into_iter().partition(|f| {
+        f.size > 0 && f.size < args.solid_under && f.size as usize >= args.inline_under
+    });
+
+    // 小さいファイルは、それらから学習した辞書を使って圧縮する
+    let dictionary = match args.dictionary {
+        true => train_dictionary(&files, !args.no_skip_compressed).map(|dictionary| &*Box::leak(dictionary.into_boxed_slice())),
+        false => None,
+    };
+
+    // 小さいファイルが大量にある時に workload の lock の取り合いにならないように、まとめて取る
+    // 最後の方で1つのワーカーにだけ仕事が残らないように、1ワーカーあたり32回くらいは取りに来る大きさにする
+    let batch_size = args.batch.unwrap_or((files.len() / (args.jobs * 32)).clamp(1, 64)).max(1);
+    let workload = Arc::new(Mutex::new(files.into_iter().enumerate().collect::<VecDeque<_>>()));
+    let sequencer = (args.reproducible || args.deterministic_dedup).then(|| Arc::new(WriteSequencer::new()));
     let outfilestr = args.output.into_os_string();
-    let outdatfile = Arc::new(Mutex::new(std::fs::File::create({
-        let mut outfile = OsString::from(&outfilestr);
-        outfile.push(".mar.dat");
-        println!("Output: {}", outfile.to_str().unwrap());
-        outfile
-    }).unwrap()));
-    let mut outidxfile = std::fs::File::create({
-        let mut outfile = OsString::from(&outfilestr);
-        outfile.push(".mar.idx");
-        outfile
-    }).unwrap();
+    let throttle = args.throttle.map(|rate| Arc::new(Throttle::new(rate)));
+    let outdatfile = Arc::new(Mutex::new(DatWriter::new(outfilestr.clone(), args.dat_split_size, throttle.clone(), args.fadvise)?));
+    // 同じ出力先に同時に create したり、書いている途中で読まれたりしないように、終わるまで排他ロックを持っておく
+    let mut outidxfile = crate::format::create_exclusive(&crate::format::idx_file_path(&outfilestr))?;
 
     // make ${input.jobs} threads
 
@@ -197,101 +1525,290 @@ pub fn main(args: Args) {
 
     struct PartialFileInfo {
         path: String,
+        raw_path: Vec<u8>,
         modified_time: Option<prost_types::Timestamp>,
+        change_detection: Option<proto::ChangeDetection>,
+        mode: u32,
         original_crc32: u32,
         original_sha256: Vec<u8>,
+        // 最初にこの SHA-256 で圧縮されたファイル
+        canonical_path: String,
+        size: u64,
+        order: usize,
     }
 
-    let mut already_well_known_hashes = Arc::new(Mutex::new(HashSet::<Vec<u8>>::new()));
+    let mut already_well_known_hashes = Arc::new(Mutex::new(HashMap::<Vec<u8>, String>::new()));
     let mut deduped_file_entries = Arc::new(Mutex::new(Vec::<PartialFileInfo>::new()));
+    let skipped_files = Arc::new(AtomicUsize::new(0));
+    let dedup_bypassed_files = Arc::new(AtomicUsize::new(0));
+    let ratio_skipped_files = Arc::new(AtomicUsize::new(0));
+    let ratio_skipped_bytes = Arc::new(AtomicU64::new(0));
+    // --flag-ratio-below に引っかかった (パス, 元の大きさ, 比率)
+    let ratio_flagged = Arc::new(Mutex::new(Vec::<(String, u64, f64)>::new()));
+
+    let compress_options = CompressOptions {
+        zstd_frames: args.zstd_frames,
+        skippable_frame_headers: args.skippable_frame_headers,
+        self_check: args.self_check,
+        text_align: false,
+        min_chunks_for_parallel: args.min_chunks_for_parallel,
+        chunk_parallel_threshold: args.chunk_parallel_threshold_bytes as usize,
+        chunk_size: args.chunk_size,
+        zstd_level: args.level,
+        best: args.best,
+        codec: args.codec.map(CompressedMethod::from),
+        dictionary,
+        min_ratio: args.min_ratio,
+    };
 
     for thread_no in 0..args.jobs {
         let workload = workload.clone();
-        let input = args.input.to_str().unwrap().to_string();
+        let input = args.input.clone();
         let outdatfile = outdatfile.clone();
         let hash_to_offsets = hash_to_offsets.clone();
         let already_well_known_hashes = already_well_known_hashes.clone();
         let deduped_file_entries = deduped_file_entries.clone();
+        let skipped_files = skipped_files.clone();
+        let dedup_bypassed_files = dedup_bypassed_files.clone();
+        let ratio_skipped_files = ratio_skipped_files.clone();
+        let ratio_skipped_bytes = ratio_skipped_bytes.clone();
+        let ratio_flagged = ratio_flagged.clone();
+        let throttle = throttle.clone();
+        let sequencer = sequencer.clone();
+        let spool_path = {
+            let mut path = outfilestr.clone();
+            path.push(format!(".mar.spool.{}", thread_no));
+            path
+        };
 
         threads.push(thread::spawn(move || {
             let mut entries = Vec::new();
-            loop {
-                let workload = workload.lock().unwrap().pop_front();
-                if let Some(file) = workload {
+            let mut batch = Batch { files: VecDeque::new(), sequencer: sequencer.clone() };
+            // 大きなファイルを圧縮しながら書いておく場所。使い回して、終わったら (失敗しても) 消す
+            let mut spool: Option<std::fs::File> = None;
+            let result = loop {
+                if batch.files.is_empty() {
+                    let mut workload = workload.lock().unwrap();
+                    let n = batch_size.min(workload.len());
+                    batch.files.extend(workload.drain(..n));
+                }
+                let workload = batch.files.pop_front();
+                if let Some((seq, file)) = workload {
+                    // drop される時に (自分の番を待ってから) 次の番に回す
+                    let turn = sequencer.as_ref().map(|sequencer| sequencer.turn(seq));
                     if file.path.file_name().unwrap() == ".DS_Store" {
+                        skipped_files.fetch_add(1, Ordering::Relaxed);
                         continue;
                     }
 
-                    let mut fp: std::fs::File = std::fs::File::open(&file.path).unwrap();
-                    let metadata = fp.metadata().unwrap();
-                    let (input_data, original_crc32, original_sha256) = {
-                        let mut crc32_hasher = crc32fast::Hasher::new();
-                        let mut sha256_hasher = sha2::Sha256::new();
-                        let mut data = Vec::<u8>::with_capacity(metadata.len() as usize);
-
-                        let mut reader = std::io::BufReader::new(&mut fp);
-                        loop {
-                            let mut buf = [0; 32768];
-                            let n = reader.read(&mut buf).unwrap();
-                            if n == 0 {
-                                break;
-                            }
-                            crc32_hasher.update(&buf[..n]);
-                            sha256_hasher.update(&buf[..n]);
-                            data.extend_from_slice(&buf[..n]);
-                        }
+                    let (relative_path, raw_path) = match archived_path(&input, &file.path) {
+                        Ok(archived) => archived,
+                        Err(e) => break Err(e),
+                    };
 
-                        (data, crc32_hasher.finalize(), sha256_hasher.finalize().to_vec())
+                    let compress_options = CompressOptions {
+                        text_align: args.text_align && is_text_path(&file.path),
+                        ..compress_options
                     };
+                    // 既に圧縮されているファイルは圧縮を試さずにそのまま入れる (--zstd-frames では全チャンクを zstd にする必要があるので試す)
+                    let compress_options = match !args.no_skip_compressed && !args.zstd_frames && is_compressed_path(&file.path) {
+                        true => CompressOptions { codec: Some(CompressedMethod::Passthrough), best: false, ..compress_options },
+                        false => compress_options,
+                    };
+
+                    let read = (|| -> Result<_, MarError> {
+                        let mut fp: std::fs::File = std::fs::File::open(&file.path).map_err(MarError::at(&file.path))?;
+                        if args.fadvise == Fadvise::Sequential {
+                            fadvise(&fp, 0, 0, Fadvise::Sequential);
+                        }
+                        let metadata = fp.metadata().map_err(MarError::at(&file.path))?;
+
+                        // 大きなファイルは丸ごとメモリに読まず、読みながら圧縮して spool に書く
+                        // (--compress-timeout は別スレッドにデータを丸ごと渡すので、今まで通りメモリに読む)
+                        let streaming = metadata.len() > WHOLE_FILE_THRESHOLD as u64 && metadata.len() >= args.inline_under as u64 && args.compress_timeout.is_none();
+                        let (size, original_crc32, original_sha256, input_data, streamed) = if streaming {
+                            if spool.is_none() {
+                                spool = Some(std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(true).open(&spool_path).map_err(MarError::at(Path::new(&spool_path)))?);
+                            }
+                            let streamed = compress_streaming(&mut std::io::BufReader::new(&mut fp), metadata.len(), spool.as_mut().unwrap(), compress_options, args.no_integrity_hash, throttle.as_deref(), &relative_path)?;
+                            (streamed.original_size, streamed.original_crc32, streamed.original_sha256, None, Some((streamed.chunk_infos, streamed.body)))
+                        } else {
+                            let mut crc32_hasher = crc32fast::Hasher::new();
+                            let mut sha256_hasher = sha2::Sha256::new();
+                            let mut data = Vec::<u8>::with_capacity(metadata.len() as usize);
+
+                            let mut reader = std::io::BufReader::new(&mut fp);
+                            loop {
+                                let mut buf = [0; 32768];
+                                let n = timed(&PROFILE.read, || reader.read(&mut buf)).map_err(MarError::at(&file.path))?;
+                                if n == 0 {
+                                    break;
+                                }
+                                if let Some(throttle) = &throttle {
+                                    throttle.consume(n);
+                                }
+                                timed(&PROFILE.hash, || {
+                                    crc32_hasher.update(&buf[..n]);
+                                    if !args.no_integrity_hash {
+                                        sha256_hasher.update(&buf[..n]);
+                                    }
+                                });
+                                data.extend_from_slice(&buf[..n]);
+                            }
 
-                    let relative_path = file.path.to_str().unwrap();
-                    assert!(relative_path.starts_with(&input));
-                    let relative_path = relative_path[input.len()..].to_string();
+                            let original_sha256 = match args.no_integrity_hash {
+                                true => Vec::new(),
+                                false => timed(&PROFILE.hash, || sha256_hasher.finalize().to_vec()),
+                            };
+                            (data.len() as u64, crc32_hasher.finalize(), original_sha256, Some(Arc::new(data)), None)
+                        };
+                        // 読み終わったのでもうキャッシュに残さなくていい
+                        if args.fadvise == Fadvise::Dontneed {
+                            fadvise(&fp, 0, 0, Fadvise::Dontneed);
+                        }
 
-                    let modified_time = fp.metadata().unwrap().modified().unwrap();
+                        let metadata = fp.metadata().map_err(MarError::at(&file.path))?;
+                        let modified_time = metadata.modified().map_err(MarError::at(&file.path))?;
+                        Ok((size, original_crc32, original_sha256, input_data, streamed, metadata, modified_time))
+                    })();
+                    // 読めなかったファイルは --best-effort なら飛ばす。そうでなければ create 全体を止める
+                    let (size, original_crc32, original_sha256, input_data, streamed, metadata, modified_time) = match read {
+                        Ok(read) => read,
+                        Err(e) if args.best_effort => {
+                            eprintln!("warning: skipping {}", e);
+                            skipped_files.fetch_add(1, Ordering::Relaxed);
+                            continue;
+                        },
+                        Err(e) => break Err(e),
+                    };
+                    let change_detection = args.change_detection_metadata.then(|| change_detection(&metadata));
+                    let mode = file_mode(&metadata);
 
                     // もしもう圧縮済みの同 SHA-256 ファイルがあればそちらを使う
-                    if args.dedup {
+                    // 小さすぎるファイルは dedup の対象にしない
+                    let should_dedup = args.dedup && size >= args.dedup_min_size;
+                    if args.dedup && !should_dedup {
+                        dedup_bypassed_files.fetch_add(1, Ordering::Relaxed);
+                    }
+                    // 既に同じ SHA-256 のファイルがあれば dedup として記録して true を返す。無ければ自分が canonical になる
+                    let dedup = || -> bool {
                         let mut already_well_known_hashes = already_well_known_hashes.lock().unwrap();
-                        if already_well_known_hashes.contains(&original_sha256) {
+                        if let Some(canonical_path) = already_well_known_hashes.get(&original_sha256) {
                             println!("dedup {}", relative_path);
                             let mut deduped_file_entries = deduped_file_entries.lock().unwrap();
                             deduped_file_entries.push(PartialFileInfo {
                                 path: relative_path.clone(),
+                                raw_path: raw_path.clone(),
                                 modified_time: Some(prost_types::Timestamp::from(modified_time)),
+                                change_detection: change_detection.clone(),
+                                mode,
                                 original_crc32,
-                                original_sha256,
+                                original_sha256: original_sha256.clone(),
+                                canonical_path: canonical_path.clone(),
+                                size,
+                                order: file.order,
                             });
+                            return true;
+                        }
+                        already_well_known_hashes.insert(original_sha256.clone(), relative_path.clone());
+                        return false;
+                    };
+                    // --reproducible, --deterministic-dedup の時は後で順番に判定する
+                    // (spool に書いたファイルは圧縮し終わってからでないとハッシュが分からないので、ここで捨てても圧縮は済んでいる)
+                    let ordered_dedup = args.reproducible || args.deterministic_dedup;
+                    if should_dedup && !ordered_dedup && dedup() {
+                        continue;
+                    }
+
+                    let (chunk_infos, body) = match streamed {
+                        Some(streamed) => streamed,
+                        None => {
+                            let input_data = input_data.unwrap();
+                            let chunks = match args.compress_timeout {
+                                None => compress_file(&input_data, compress_options),
+                                Some(timeout) => {
+                                    match compress_file_with_timeout(input_data.clone(), compress_options, std::time::Duration::from_secs(timeout)) {
+                                        Some(chunks) => chunks,
+                                        None => {
+                                            println!("{}: compression took longer than {}s, falling back to a faster codec", relative_path, timeout);
+                                            compress_file_fast(&input_data, compress_options)
+                                        },
+                                    }
+                                },
+                            };
+
+                            if compress_options.self_check {
+                                self_check_chunks(&input_data, &chunks, compress_options.dictionary.unwrap_or_default(), &relative_path);
+                            }
+
+                            let mut chunk_infos = Vec::<proto::ChunkInfo>::with_capacity(chunks.len());
+                            let mut compressed = Vec::new();
+                            for mut chunk in chunks {
+                                if compress_options.skippable_frame_headers {
+                                    add_skippable_frame_header(&mut chunk);
+                                }
+                                chunk_infos.push(proto::ChunkInfo {
+                                    compressed_length: chunk.compressed.len() as u32,
+                                    compressed_method: chunk.compressed_method as i32,
+                                    original_length: chunk.original_size as u32,
+                                    using_dictionary: chunk.using_dictionary,
+                                });
+                                compressed.append(&mut chunk.compressed);
+                            }
+                            (chunk_infos, CompressedBody::Memory(compressed))
+                        },
+                    };
+                    println!("{}: {} ({} chunks, {} -> {} bytes)", thread_no, relative_path, chunk_infos.len(), size, body.len());
+
+                    // あまり縮まなかったファイルは .dat に書く前に捨てる
+                    if let Some(max_ratio) = args.skip_if_ratio_above {
+                        let ratio = body.len() as f64 / size as f64;
+                        if size > 0 && ratio > max_ratio {
+                            println!("skip {} (ratio {:.3} is above --skip-if-ratio-above)", relative_path, ratio);
+                            ratio_skipped_files.fetch_add(1, Ordering::Relaxed);
+                            ratio_skipped_bytes.fetch_add(size, Ordering::Relaxed);
                             continue;
                         }
-                        already_well_known_hashes.insert(original_sha256.clone());
+                    }
+                    if let Some(min_ratio) = args.flag_ratio_below {
+                        let ratio = body.len() as f64 / size as f64;
+                        if size > 0 && ratio < min_ratio {
+                            ratio_flagged.lock().unwrap().push((relative_path.clone(), size, ratio));
+                        }
                     }
 
-                    let chunks = compress_file(&input_data);
-
-                    let mut chunk_infos = Vec::<proto::ChunkInfo>::with_capacity(chunks.len());
-                    let mut compressed = Vec::new();
-                    for mut chunk in chunks {
-                        chunk_infos.push(proto::ChunkInfo {
-                            compressed_length: chunk.compressed.len() as u32,
-                            compressed_method: chunk.compressed_method as i32,
-                            original_length: chunk.original_size as u32,
-                        });
-                        compressed.append(&mut chunk.compressed);
+                    // --reproducible の時は自分の番を待ってから dedup を判定して書き込む
+                    // (どちらが canonical になるかや .dat 上の位置が、終わった順番に左右されないように)
+                    if let Some(turn) = &turn {
+                        turn.wait();
+                    }
+                    if should_dedup && ordered_dedup && dedup() {
+                        continue;
+                    }
+                    // --deterministic-dedup だけなら書き込みは順番を待たなくていい
+                    if !args.reproducible {
+                        drop(turn);
                     }
-                    println!("{}: {} ({} chunks, {} -> {} bytes)", thread_no, relative_path, chunk_infos.len(), input_data.len(), compressed.len());
 
                     use sha2::Digest;
 
                     let entry = {
                         let mut hash_to_offsets = hash_to_offsets.lock().unwrap();
 
+                        let (chunks_crc32, chunks_sha256) = match &body {
+                            CompressedBody::Memory(compressed) => (crc32fast::hash(compressed), match args.no_integrity_hash {
+                                true => Vec::new(),
+                                false => sha2::Sha256::digest(compressed).to_vec(),
+                            }),
+                            CompressedBody::Spooled { crc32, sha256, .. } => (*crc32, sha256.clone()),
+                        };
+
                         let file_info = proto::FileInfo {
                             path: relative_path,
                             chunks: chunk_infos,
     
-                            chunks_crc32: crc32fast::hash(&compressed),
-                            chunks_sha256: sha2::Sha256::digest(&compressed).to_vec(),
+                            chunks_crc32,
+                            chunks_sha256,
     
                             original_crc32,
                             original_sha256,
@@ -299,35 +1816,63 @@ pub fn main(args: Args) {
                             modified_time: Some(prost_types::Timestamp::from(modified_time)),
                             // dictionary_size: 0,
                             priority: 0,
+                            change_detection,
+                            symlink_target: String::new(),
+                            mode,
+                            raw_path,
+                            raw_symlink_target: Vec::new(),
                         };
 
-                        let offset = {
-                            let mut outdatfile = outdatfile.lock().unwrap();
-                            let offset = outdatfile.seek(std::io::SeekFrom::End(0)).unwrap();
-                            outdatfile.write_all(&compressed).unwrap();
+                        // 十分小さいファイルは .dat に書かずに index に直接入れる
+                        let is_inline = size < args.inline_under as u64;
 
-                            offset
+                        let body_size = body.len();
+                        let written = match body {
+                            CompressedBody::Memory(compressed) if is_inline => Ok((0, 0, compressed)),
+                            CompressedBody::Memory(compressed) => {
+                                let written = if args.concurrency_safe_append {
+                                    write_body_unlocked(&outdatfile, &compressed)
+                                } else {
+                                    outdatfile.lock().unwrap().write_body(&compressed)
+                                };
+                                written.map(|(file_index, offset)| (file_index, offset, Vec::new()))
+                            },
+                            CompressedBody::Spooled { len, .. } => {
+                                write_spooled_body(&outdatfile, spool.as_mut().unwrap(), len, args.concurrency_safe_append).map(|(file_index, offset)| (file_index, offset, Vec::new()))
+                            },
+                        };
+                        let (file_index, offset, inline_body) = match written {
+                            Ok(written) => written,
+                            Err(e) => break Err(e),
                         };
 
                         let entry = proto::FileEntry {
                             info: Some(file_info),
-                            file_index: 0,
+                            file_index,
                             body_offset: offset,
-                            body_size: compressed.len() as u64,
+                            body_size,
+                            inline_body,
+                            in_solid_blob: false,
+                            solid_offset: 0,
+                            solid_length: 0,
                         };
 
-                        if args.dedup {
+                        if should_dedup {
                             hash_to_offsets.insert(entry.info.as_ref().unwrap().original_sha256.clone(), entry.clone());
                         }
 
                         entry
                     };
 
-                    entries.push(entry);
+                    entries.push((file.order, entry));
                 } else {
-                    break entries;
+                    break Ok(entries);
                 }
+            };
+            if spool.take().is_some() {
+                _ = std::fs::remove_file(&spool_path);
             }
+            result
         }));
     }
 
@@ -335,7 +1880,15 @@ pub fn main(args: Args) {
 
     let mut ees = Vec::with_capacity(files_count);
     for thread in threads {
-        for e in thread.join().unwrap() {
+        let thread_entries = match thread.join() {
+            Ok(entries) => entries?,
+            Err(_) if args.best_effort => {
+                eprintln!("warning: worker thread panicked, its files are missing from the archive");
+                continue;
+            },
+            Err(e) => std::panic::resume_unwind(e),
+        };
+        for e in thread_entries {
             // entries.push(FileEntry {
             //     path: e.path.to_str().unwrap().to_string(),
             //     compressed_method: e.compressed_method,
@@ -349,37 +1902,410 @@ pub fn main(args: Args) {
         }
     }
 
+    let input = args.input.as_path();
+    for group in group_solid_files(solid_files) {
+        let group = group.into_iter().filter(|file| {
+            if file.path.file_name().unwrap() == ".DS_Store" {
+                skipped_files.fetch_add(1, Ordering::Relaxed);
+                return false;
+            }
+            true
+        }).collect::<Vec<_>>();
+        ees.append(&mut compress_solid_blob(&group, input, &outdatfile, compress_options, args.no_integrity_hash, args.change_detection_metadata, throttle.as_deref())?);
+    }
+
+    ees.append(&mut symlink_entries(&symlinks, input, args.no_integrity_hash, args.change_detection_metadata)?);
+
     let hash_to_offsets = hash_to_offsets.lock().unwrap();
+    let mut dedup_report = Vec::new();
+    let mut dedup_stats = DedupStats { files: 0, bytes_saved: 0 };
     for e in deduped_file_entries.lock().unwrap().drain(0..) {
-        let dedup_target = hash_to_offsets.get(&e.original_sha256).unwrap().clone();
+        let dedup_target = match hash_to_offsets.get(&e.original_sha256) {
+            Some(dedup_target) => dedup_target.clone(),
+            // 中身が同じなので、canonical が --skip-if-ratio-above で捨てられていたらこれも捨てる
+            None if args.skip_if_ratio_above.is_some() => {
+                println!("skip {} (same content as {})", e.path, e.canonical_path);
+                ratio_skipped_files.fetch_add(1, Ordering::Relaxed);
+                ratio_skipped_bytes.fetch_add(e.size, Ordering::Relaxed);
+                continue;
+            },
+            None => panic!("dedup target of {} is missing", e.path),
+        };
         assert!(dedup_target.info.as_ref().unwrap().original_sha256 == e.original_sha256);
         assert!(dedup_target.info.as_ref().unwrap().original_crc32 == e.original_crc32);
-        ees.push(proto::FileEntry {
+        if args.dedup_report {
+            dedup_report.push((e.path.clone(), e.canonical_path.clone(), dedup_target.body_size));
+        }
+        dedup_stats.files += 1;
+        dedup_stats.bytes_saved += dedup_target.body_size;
+        ees.push((e.order, proto::FileEntry {
             info: Some(proto::FileInfo {
                 path: e.path,
+                raw_path: e.raw_path,
                 modified_time: e.modified_time,
+                change_detection: e.change_detection,
+                mode: e.mode,
                 ..dedup_target.info.as_ref().unwrap().clone()
             }),
             ..dedup_target
-        });
+        }));
+    }
+
+    let ratio_skipped_files = ratio_skipped_files.load(Ordering::Relaxed);
+    if args.skip_if_ratio_above.is_some() {
+        println!("{} files were skipped by --skip-if-ratio-above ({} bytes excluded)", ratio_skipped_files, ratio_skipped_bytes.load(Ordering::Relaxed));
+    }
+
+    if let Some(min_ratio) = args.flag_ratio_below {
+        let mut flagged = std::mem::take(&mut *ratio_flagged.lock().unwrap());
+        flagged.sort_by(|a, b| a.0.cmp(&b.0));
+        println!("{} files compressed below --flag-ratio-below {}:", flagged.len(), min_ratio);
+        for (path, size, ratio) in flagged {
+            println!("  {} ({} bytes, ratio {:.5})", path, size, ratio);
+        }
+    }
+
+    if args.dedup_min_size > 0 {
+        println!("{} files were smaller than --dedup-min-size and skipped dedup", dedup_bypassed_files.load(Ordering::Relaxed));
+    }
+
+    if args.dedup_report {
+        dedup_report.sort();
+        println!("Dedup report:");
+        for (path, canonical_path, saved) in &dedup_report {
+            println!("{}\t-> {}\t({} bytes saved)", path, canonical_path, saved);
+        }
+        println!("{} files deduplicated, {} bytes saved", dedup_report.len(), dedup_report.iter().map(|(_, _, saved)| saved).sum::<u64>());
+    }
+
+    // 取りこぼしたファイルが無いか確認する
+    let skipped_files = skipped_files.load(Ordering::Relaxed);
+    // 数え間違いを見つけるための確認なので、引き算が負になってもそれも合わないものとして扱う
+    let expected_entries = files_count.checked_sub(skipped_files).and_then(|n| n.checked_sub(ratio_skipped_files));
+    let expected = match expected_entries {
+        Some(expected_entries) => expected_entries.to_string(),
+        None => "less than 0".to_string(),
+    };
+    println!("Entries: {} (files: {} - skipped: {} - skipped by ratio: {} = expected: {})", ees.len(), files_count, skipped_files, ratio_skipped_files, expected);
+    if expected_entries != Some(ees.len()) {
+        if args.best_effort {
+            eprintln!("warning: expected {} entries but got {}, writing index anyway", expected, ees.len());
+        } else {
+            return Err(MarError::Failed { reason: format!("expected {} entries ({} files - {} skipped - {} skipped by ratio) but got {}, some files were lost", expected, files_count, skipped_files, ratio_skipped_files, ees.len()) });
+        }
     }
 
     let enc_end = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
 
     let dec_start = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
-    ees.sort_by(|a, b| a.info.as_ref().unwrap().path.cmp(&b.info.as_ref().unwrap().path));
+    if args.preserve_order {
+        ees.sort_by_key(|(order, _)| *order);
+    } else if args.group_by_hash {
+        // 中身が同じファイルが隣り合うように並べる
+        ees.sort_by(|(_, a), (_, b)| {
+            let a = a.info.as_ref().unwrap();
+            let b = b.info.as_ref().unwrap();
+            (&a.original_sha256, &a.path).cmp(&(&b.original_sha256, &b.path))
+        });
+    } else {
+        ees.sort_by(|(_, a), (_, b)| a.info.as_ref().unwrap().path.cmp(&b.info.as_ref().unwrap().path));
+    }
+    let entries = ees.into_iter().map(|(_, e)| e).collect::<Vec<_>>();
     let index_file = proto::FileIndexFile {
-        entries: ees,
+        content_sha256: crate::format::index_file::content_sha256(&entries),
+        entries,
+        directories: directory_entries(&args.input, &directories)?,
+        zstd_dictionary: dictionary.map(|dictionary| dictionary.to_vec()).unwrap_or_default(),
     };
-    let index_file_bytes = index_file.encode_to_vec();
-    let index_file_len = index_file_bytes.len();
-    let index_file_bytes = zstd::encode_all(&index_file_bytes[..], 22).unwrap();
-
-    outidxfile.write_all(b"MARI").unwrap();
-    outidxfile.write_all(&(index_file_bytes.len() as u32).to_be_bytes()).unwrap();
-    outidxfile.write_all(&(index_file_len as u32).to_be_bytes()).unwrap();
-    outidxfile.write_all(&index_file_bytes).unwrap();
+    timed(&PROFILE.write_index, || crate::format::index_file::write_index_file_with_level(&mut outidxfile, &index_file, args.index_codec.into(), args.index_level))?;
+    if args.verify_index_roundtrip {
+        verify_index_roundtrip(&mut outidxfile, &index_file)?;
+    }
+    if args.index_only_output {
+        let catalog = catalog_index(&index_file);
+        let mut catalog_file = crate::format::create_exclusive(&crate::format::catalog_file_path(&outfilestr))?;
+        crate::format::index_file::write_index_file_with_level(&mut catalog_file, &catalog, args.index_codec.into(), args.index_level)?;
+    }
 
     let dec_end = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_millis();
     println!("{},{}", enc_end - enc_start, dec_end - dec_start);
+
+    if args.profile {
+        let profile = PROFILE.stats();
+        println!("Profile (summed over all threads, so phases can add up to more than the wall time):");
+        println!("  walk            {} ms", profile.walk_ms);
+        println!("  read            {} ms", profile.read_ms);
+        println!("  hash            {} ms", profile.hash_ms);
+        println!("  compress zstd   {} ms", profile.compress_zstd_ms);
+        println!("  compress lz4    {} ms", profile.compress_lz4_ms);
+        println!("  compress brotli {} ms", profile.compress_brotli_ms);
+        println!("  write .dat      {} ms", profile.write_dat_ms);
+        println!("  write index     {} ms", profile.write_index_ms);
+    }
+
+    if args.report_largest > 0 {
+        // solid blob の中のファイルの body_size は blob 全体のものなので、元の大きさの割合で按分する
+        let stored_size = |e: &proto::FileEntry| -> u64 {
+            if !e.in_solid_blob {
+                return e.body_size;
+            }
+            let blob_size: u64 = e.info.as_ref().unwrap().chunks.iter().map(|c| c.original_length as u64).sum();
+            return (e.body_size as u128 * e.solid_length as u128 / blob_size.max(1) as u128) as u64;
+        };
+        let mut largest = index_file.entries.iter().collect::<Vec<_>>();
+        largest.sort_by_key(|e| std::cmp::Reverse(stored_size(e)));
+        println!("Largest {} files:", args.report_largest.min(largest.len()));
+        for e in largest.iter().take(args.report_largest) {
+            let info = e.info.as_ref().unwrap();
+            println!("{}\t{} -> {} bytes", info.path, body::original_size(e), stored_size(e));
+        }
+    }
+
+    if args.audit {
+        let dats = outdatfile.lock().unwrap().files();
+        let failed = audit(&args.input, &outfilestr, &dats, &index_file.entries, &index_file.zstd_dictionary);
+        if failed > 0 {
+            return Err(MarError::Failed { reason: format!("audit failed: {} of {} files differ from the source", failed, index_file.entries.len()) });
+        }
+        println!("audit passed: {} files", index_file.entries.len());
+    }
+
+    if missing_paths > 0 {
+        println!("{} listed paths were missing from the input and skipped", missing_paths);
+    }
+
+    if let Some(stats_json) = &args.stats_json {
+        let stats = build_stats(&index_file.entries, skipped_files + ratio_skipped_files, dedup_stats, started.elapsed(), args.profile);
+        std::fs::write(stats_json, serde_json::to_vec_pretty(&stats).unwrap()).map_err(MarError::at(stats_json))?;
+    }
+    Ok(())
+}
+
+// --stats-json の中身
+#[derive(serde::Serialize)]
+struct Stats {
+    files: usize,
+    skipped_files: usize,
+    // 元のファイルの合計サイズ
+    bytes_in: u64,
+    // .dat と inline_body の合計サイズ (dedup や solid で共有されている body は1回だけ数える)
+    bytes_out: u64,
+    ratio: f64,
+    codecs: BTreeMap<String, CodecStats>,
+    dedup: DedupStats,
+    wall_time_ms: u64,
+    cpu_time_ms: Option<u64>,
+    peak_memory_bytes: Option<u64>,
+    // --profile の時だけ
+    #[serde(skip_serializing_if = "Option::is_none")]
+    profile: Option<ProfileStats>,
+}
+
+#[derive(Default, serde::Serialize)]
+struct CodecStats {
+    chunks: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+#[derive(serde::Serialize)]
+struct DedupStats {
+    files: u64,
+    bytes_saved: u64,
+}
+
+fn build_stats(entries: &[proto::FileEntry], skipped_files: usize, dedup: DedupStats, wall_time: std::time::Duration, profile: bool) -> Stats {
+    let mut bytes_in = 0;
+    let mut bytes_out = 0;
+    let mut codecs = BTreeMap::<String, CodecStats>::new();
+    // 空の body は次の body と同じ offset になるので、長さも含めて見分ける
+    let mut seen_bodies = std::collections::HashSet::new();
+    for entry in entries {
+        let info = entry.info.as_ref().unwrap();
+        bytes_in += match entry.in_solid_blob {
+            true => entry.solid_length,
+            false => info.chunks.iter().map(|c| c.original_length as u64).sum(),
+        };
+        let is_inline = !entry.inline_body.is_empty();
+        if !is_inline && !seen_bodies.insert((entry.file_index, entry.body_offset, entry.body_size)) {
+            continue;
+        }
+        bytes_out += if is_inline { entry.inline_body.len() as u64 } else { entry.body_size };
+        for chunk in &info.chunks {
+            let method = CompressedMethod::try_from(chunk.compressed_method).map(|m| m.as_str_name().to_ascii_lowercase()).unwrap_or_else(|_| chunk.compressed_method.to_string());
+            let codec = codecs.entry(method).or_default();
+            codec.chunks += 1;
+            codec.bytes_in += chunk.original_length as u64;
+            codec.bytes_out += chunk.compressed_length as u64;
+        }
+    }
+    let (cpu_time_ms, peak_memory_bytes) = resource_usage();
+    return Stats {
+        files: entries.len(),
+        skipped_files,
+        bytes_in,
+        bytes_out,
+        ratio: if bytes_in == 0 { 1.0 } else { bytes_out as f64 / bytes_in as f64 },
+        codecs,
+        dedup,
+        wall_time_ms: wall_time.as_millis() as u64,
+        cpu_time_ms,
+        peak_memory_bytes,
+        profile: profile.then(|| PROFILE.stats()),
+    };
+}
+
+// (CPU 時間, 最大 RSS)
+#[cfg(unix)]
+fn resource_usage() -> (Option<u64>, Option<u64>) {
+    let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, &mut usage) } != 0 {
+        return (None, None);
+    }
+    let to_ms = |t: libc::timeval| t.tv_sec as u64 * 1000 + t.tv_usec as u64 / 1000;
+    let cpu_time_ms = to_ms(usage.ru_utime) + to_ms(usage.ru_stime);
+    // macOS はバイト、Linux は KiB
+    let peak_memory_bytes = if cfg!(target_os = "macos") { usage.ru_maxrss as u64 } else { usage.ru_maxrss as u64 * 1024 };
+    return (Some(cpu_time_ms), Some(peak_memory_bytes));
+}
+
+#[cfg(not(unix))]
+fn resource_usage() -> (Option<u64>, Option<u64>) {
+    return (None, None);
+}
+
+// 書いた .mar.idx を読み直して、メモリ上の index と同じか確認する
+// Windows ではロックが強制なので、開き直さずに書いた (ロックを持っている) ハンドルから読む
+fn verify_index_roundtrip(index: &mut std::fs::File, index_file: &proto::FileIndexFile) -> Result<(), MarError> {
+    use std::io::Seek;
+
+    index.rewind()?;
+    let read_back = crate::format::index_file::parse_index_file(index).map_err(|e| MarError::Failed { reason: format!("index roundtrip failed, can't parse the written index: {}", e) })?;
+    if read_back == *index_file {
+        println!("index roundtrip: ok");
+        return Ok(());
+    }
+
+    if read_back.entries.len() != index_file.entries.len() {
+        eprintln!("expected {} entries, read back {}", index_file.entries.len(), read_back.entries.len());
+    }
+    if read_back.content_sha256 != index_file.content_sha256 {
+        eprintln!("content_sha256 differs");
+    }
+    if let Some(i) = index_file.entries.iter().zip(&read_back.entries).position(|(a, b)| a != b) {
+        eprintln!("first mismatching entry #{}", i);
+        eprintln!("written: {:#?}", index_file.entries[i]);
+        eprintln!("read back: {:#?}", read_back.entries[i]);
+    }
+    return Err(MarError::Failed { reason: "index roundtrip failed".to_string() });
+}
+
+// .dat の中の位置と inline_body を消した index を作る
+// チャンクの original_length はファイルサイズの計算に要るので残す
+fn catalog_index(index_file: &proto::FileIndexFile) -> proto::FileIndexFile {
+    let entries = index_file.entries.iter().map(|e| proto::FileEntry {
+        file_index: 0,
+        body_offset: 0,
+        body_size: 0,
+        inline_body: Vec::new(),
+        ..e.clone()
+    }).collect();
+    return proto::FileIndexFile { entries, content_sha256: index_file.content_sha256.clone(), directories: index_file.directories.clone(), zstd_dictionary: Vec::new() };
+}
+
+// input の下にある path を、index に書く形 ("/foo/bar") にする。UTF-8 でなければ元のバイト列も返す
+// --input の書き方 ("dir", "dir/", "./dir") や OS によらず、区切りは "/" で先頭にも "/" を付ける
+fn archived_path(input: &Path, path: &Path) -> Result<(String, Vec<u8>), MarError> {
+    let relative = path.strip_prefix(input).map_err(|_| MarError::Failed { reason: format!("{} is not under --input {}", path.display(), input.display()) })?;
+    #[cfg(unix)]
+    let archived = {
+        use std::os::unix::ffi::OsStrExt;
+        let mut archived = Vec::new();
+        for component in relative.components() {
+            archived.push(b'/');
+            archived.extend_from_slice(component.as_os_str().as_bytes());
+        }
+        crate::format::encode_path(std::ffi::OsStr::from_bytes(&archived))
+    };
+    // Windows では raw_path は使わない
+    #[cfg(not(unix))]
+    let archived = {
+        let mut archived = String::new();
+        for component in relative.components() {
+            archived.push('/');
+            archived.push_str(&component.as_os_str().to_string_lossy());
+        }
+        (archived, Vec::new())
+    };
+    return Ok(archived);
+}
+
+// 空のディレクトリも展開した時に残るように、ディレクトリも (ファイルと同じ形のパスで) index に入れる
+fn directory_entries(input: &PathBuf, directories: &[PathBuf]) -> Result<Vec<proto::DirectoryEntry>, MarError> {
+    let mut entries = directories.iter().map(|dir| {
+        let (path, raw_path) = archived_path(input, dir)?;
+        Ok(proto::DirectoryEntry {
+            path,
+            raw_path,
+            modified_time: dir.metadata().and_then(|m| m.modified()).ok().map(prost_types::Timestamp::from),
+        })
+    }).collect::<Result<Vec<_>, MarError>>()?;
+    entries.sort_by(|a, b| a.path.cmp(&b.path).then_with(|| a.raw_path.cmp(&b.raw_path)));
+    return Ok(entries);
+}
+
+// 書き込んだアーカイブを展開して元ファイルと突き合わせる
+// dats は書いた時のハンドル (Windows ではロックが強制なので開き直すと読めない)
+fn audit(input: &PathBuf, archive: &OsString, dats: &[Arc<std::fs::File>], entries: &[proto::FileEntry], dictionary: &[u8]) -> usize {
+    let mut failed = 0;
+    for entry in entries {
+        let info = entry.info.as_ref().unwrap();
+        let source_path = input.join(crate::format::relative_path(&info.path, &info.raw_path));
+        // symlink の entry には body が無いので、リンク先が同じかを比べる
+        if !info.symlink_target.is_empty() {
+            let target = crate::format::decode_path(&info.symlink_target, &info.raw_symlink_target);
+            match std::fs::read_link(&source_path) {
+                Ok(source) if source.as_os_str() == target => {},
+                Ok(source) => {
+                    println!("audit: {}: source links to {}, archived link points to {}", info.path, source.display(), info.symlink_target);
+                    failed += 1;
+                },
+                Err(e) => {
+                    println!("audit: {}: failed to read source link: {}", info.path, e);
+                    failed += 1;
+                },
+            }
+            continue;
+        }
+        // .dat が無い時もその entry の失敗として数える
+        let mut dat = match dats.get(entry.file_index as usize) {
+            Some(dat) => &**dat,
+            None => {
+                println!("audit: {}: {} was not written", info.path, crate::format::dat_file_path(archive, entry.file_index).to_string_lossy());
+                failed += 1;
+                continue;
+            },
+        };
+        let source = match std::fs::read(&source_path) {
+            Ok(source) => source,
+            Err(e) => {
+                println!("audit: {}: failed to read source: {}", info.path, e);
+                failed += 1;
+                continue;
+            },
+        };
+        let archived = body::read_compressed_body(&mut dat, entry).and_then(|b| body::decode_entry_body(entry, &b, dictionary));
+        match archived {
+            Ok(archived) if archived == source => {},
+            Ok(_) => {
+                println!("audit: {}: archived content differs from source", info.path);
+                failed += 1;
+            },
+            Err(e) => {
+                println!("audit: {}: {}", info.path, e);
+                failed += 1;
+            },
+        }
+    }
+    return failed;
 }
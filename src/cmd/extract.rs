@@ -0,0 +1,173 @@
+use std::{ffi::OsStr, path::{Path, PathBuf}, time::SystemTime};
+
+use clap::Parser;
+
+use crate::{error::MarError, format};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Archive to read (the name given to `create --output`)
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Directory to extract into
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Memory-map the .dat files instead of reading each body with read(2) (same as --read-strategy mmap)
+    #[arg(long)]
+    mmap: bool,
+
+    /// Also restore setuid, setgid and sticky bits (only the rwx bits are restored by default)
+    #[arg(long)]
+    preserve_special_bits: bool,
+}
+
+pub fn main(args: Args) -> Result<(), MarError> {
+    let reader = crate::cmd::open_reader(&args.input, args.mmap)?;
+
+    // 空のディレクトリも作る
+    let mut directories = Vec::new();
+    for dir in &reader.index().directories {
+        let path = output_path(&args.output, &dir.path, &dir.raw_path)?;
+        check_no_symlink_ancestor(&args.output, &path)?;
+        // ディレクトリ自体が output に元からある symlink でも、create_dir_all と更新日時はリンク先に効いてしまう
+        if std::fs::symlink_metadata(&path).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(MarError::PathTraversal(path));
+        }
+        std::fs::create_dir_all(&path)?;
+        directories.push((path, dir.modified_time.clone()));
+    }
+
+    // symlink は普通のファイルを全部書いてから作る (先に作ると、後のファイルがリンクを辿って output の外に書かれてしまう)
+    let mut symlinks = Vec::new();
+    for entry in reader.entries() {
+        let info = entry.info.as_ref().unwrap();
+        let path = output_path(&args.output, &info.path, &info.raw_path)?;
+        if !info.symlink_target.is_empty() {
+            symlinks.push((path, info));
+            continue;
+        }
+        check_no_symlink_ancestor(&args.output, &path)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let decoded = reader.read_file(entry)?;
+        write_file(&path, &decoded)?;
+        set_mode(&path, info.mode, args.preserve_special_bits)?;
+        if let Some(modified_time) = info.modified_time.clone() {
+            set_modified_time(&path, modified_time)?;
+        }
+        println!("{}", info.path);
+    }
+
+    for (path, info) in symlinks {
+        // 先に作った symlink の下に作ろうとしていたら、それも外に出てしまうので弾く
+        check_no_symlink_ancestor(&args.output, &path)?;
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        create_symlink(&format::decode_path(&info.symlink_target, &info.raw_symlink_target), &path)?;
+        if let Some(modified_time) = info.modified_time.clone() {
+            let modified_time = SystemTime::try_from(modified_time).map_err(|e| MarError::Corrupt { detail: format!("{}: bad modified_time: {}", path.display(), e) })?;
+            let modified_time = filetime::FileTime::from_system_time(modified_time);
+            filetime::set_symlink_file_times(&path, modified_time, modified_time)?;
+        }
+        println!("{} -> {}", info.path, info.symlink_target);
+    }
+
+    // 中にファイルを作ると更新日時が変わるので最後に、子から順に戻す
+    directories.sort_by(|a, b| b.0.cmp(&a.0));
+    for (path, modified_time) in directories {
+        if let Some(modified_time) = modified_time {
+            set_modified_time(&path, modified_time)?;
+        }
+    }
+    Ok(())
+}
+
+// info.path は "/foo/bar" (古い版が Windows で作ったものなら "\foo\bar")。output の外に書かないように .. などは弾く
+fn output_path(output: &Path, archived: &str, raw_path: &[u8]) -> Result<PathBuf, MarError> {
+    let path = output.join(format::contained_relative_path(archived, raw_path)?);
+    if path == output {
+        return Err(MarError::Corrupt { detail: format!("{:?}: empty path", archived) });
+    }
+    Ok(path)
+}
+
+// output から path までの途中に symlink があると、その先 (output の外かもしれない) に書いてしまうので弾く
+// アーカイブの中の symlink だけでなく、output に元からあったものも同じ
+fn check_no_symlink_ancestor(output: &Path, path: &Path) -> Result<(), MarError> {
+    let mut current = output.to_path_buf();
+    let relative = path.strip_prefix(output).unwrap_or(path);
+    let mut components = relative.components().peekable();
+    while let Some(component) = components.next() {
+        if components.peek().is_none() {
+            break;
+        }
+        current.push(component);
+        if std::fs::symlink_metadata(&current).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(MarError::PathTraversal(current));
+        }
+    }
+    Ok(())
+}
+
+// path 自体が symlink だったら辿らずにエラーにする
+#[cfg(unix)]
+fn write_file(path: &Path, data: &[u8]) -> Result<(), MarError> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).custom_flags(libc::O_NOFOLLOW).open(path).map_err(MarError::at(path))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn write_file(path: &Path, data: &[u8]) -> Result<(), MarError> {
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink()) {
+        return Err(MarError::PathTraversal(path.to_path_buf()));
+    }
+    std::fs::write(path, data)?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &OsStr, path: &Path) -> Result<(), MarError> {
+    std::os::unix::fs::symlink(target, path)?;
+    Ok(())
+}
+
+// Windows ではリンク先がディレクトリかどうかで作り方が違う。分からない (リンク切れ) ならファイルとして作る
+#[cfg(windows)]
+fn create_symlink(target: &OsStr, path: &Path) -> Result<(), MarError> {
+    if path.parent().unwrap_or(Path::new("")).join(target).is_dir() {
+        std::os::windows::fs::symlink_dir(target, path)?;
+    } else {
+        std::os::windows::fs::symlink_file(target, path)?;
+    }
+    Ok(())
+}
+
+// 0 は記録されていない (Windows で作った、古いアーカイブ) のでそのまま
+// アーカイブを作った人が選んだ setuid のファイルを root で展開して作ってしまわないように、特殊なビットは頼まれた時だけ戻す
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32, preserve_special_bits: bool) -> Result<(), MarError> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = mode & if preserve_special_bits { 0o7777 } else { 0o777 };
+    if mode != 0 {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32, _preserve_special_bits: bool) -> Result<(), MarError> {
+    Ok(())
+}
+
+fn set_modified_time(path: &Path, modified_time: prost_types::Timestamp) -> Result<(), MarError> {
+    let modified_time = SystemTime::try_from(modified_time).map_err(|e| MarError::Corrupt { detail: format!("{}: bad modified_time: {}", path.display(), e) })?;
+    filetime::set_file_mtime(path, filetime::FileTime::from_system_time(modified_time))?;
+    Ok(())
+}
@@ -0,0 +1,79 @@
+use std::{collections::{HashMap, HashSet}, path::PathBuf};
+
+use clap::Parser;
+
+use crate::{error::MarError, format::body, proto::CompressedMethod};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Archive to read (the name given to `create --output`)
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Print sizes as KiB/MiB/GiB instead of bytes
+    #[arg(long)]
+    human: bool,
+}
+
+pub fn main(args: Args) -> Result<(), MarError> {
+    let reader = crate::cmd::open_reader(&args.input, false)?;
+    let size = |bytes: u64| if args.human { human_size(bytes) } else { bytes.to_string() };
+
+    let mut total_original = 0;
+    let mut total_stored = 0;
+    // dedup や solid blob で共有されている body は合計に1回だけ数える
+    // (空の body は次の body と同じ offset になるので、長さも含めて見分ける)
+    let mut seen_bodies = HashSet::new();
+    for entry in reader.entries() {
+        let info = entry.info.as_ref().unwrap();
+        let original = body::original_size(entry);
+        let stored = if entry.inline_body.is_empty() { entry.body_size } else { entry.inline_body.len() as u64 };
+
+        // 一番多くのバイトを展開するチャンクの圧縮方式
+        let mut methods = HashMap::<i32, u64>::new();
+        for chunk in &info.chunks {
+            *methods.entry(chunk.compressed_method).or_default() += chunk.original_length as u64;
+        }
+        let method = methods.into_iter().max_by_key(|&(method, bytes)| (bytes, -method))
+            .map(|(method, _)| CompressedMethod::try_from(method).map(|m| m.as_str_name()).unwrap_or("UNKNOWN"))
+            .unwrap_or("-");
+
+        let mut notes = Vec::new();
+        if !entry.inline_body.is_empty() {
+            notes.push("inline");
+        }
+        let symlink = format!("symlink -> {}", info.symlink_target);
+        if !info.symlink_target.is_empty() {
+            notes.push(symlink.as_str());
+        }
+        if entry.in_solid_blob {
+            // stored は blob 全体の大きさ
+            notes.push("solid");
+        }
+        let notes = if notes.is_empty() { String::new() } else { format!(" ({})", notes.join(", ")) };
+        println!("{}\t{}\t{}\t{}\t{}{}", size(original), size(stored), info.chunks.len(), method, info.path, notes);
+
+        total_original += original;
+        if !entry.inline_body.is_empty() || seen_bodies.insert((entry.file_index, entry.body_offset, entry.body_size)) {
+            total_stored += stored;
+        }
+    }
+
+    let ratio = if total_original == 0 { 1.0 } else { total_stored as f64 / total_original as f64 };
+    println!("{} files, {} -> {} ({:.1}%)", reader.entries().len(), size(total_original), size(total_stored), ratio * 100.0);
+    Ok(())
+}
+
+fn human_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{}B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1}{}", size, UNITS[unit])
+}
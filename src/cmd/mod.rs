@@ -1,2 +1,96 @@
+use std::{ffi::OsStr, sync::atomic::{AtomicBool, Ordering}};
+
+use clap::ValueEnum;
+
+use crate::{error::MarError, proto::CompressedMethod};
+
 pub mod create;
-pub mod showsum;
\ No newline at end of file
+pub mod showsum;
+pub mod recompress;
+pub mod which;
+pub mod verify_tree;
+pub mod repack;
+pub mod extract;
+pub mod cat;
+pub mod verify;
+pub mod list;
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum Codec {
+    Zstd,
+    Lz4,
+    Passthrough,
+    Brotli,
+}
+
+impl From<Codec> for CompressedMethod {
+    fn from(codec: Codec) -> Self {
+        match codec {
+            Codec::Zstd => CompressedMethod::Zstandard,
+            Codec::Lz4 => CompressedMethod::Lz4,
+            Codec::Passthrough => CompressedMethod::Passthrough,
+            Codec::Brotli => CompressedMethod::Brotli,
+        }
+    }
+}
+
+// --read-strategy。mmap はネットワーク越しのファイルシステムなどでは逆に遅かったり落ちたりするので、既定は seek
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum ReadStrategy {
+    Seek,
+    Mmap,
+}
+
+static READ_MMAP: AtomicBool = AtomicBool::new(false);
+
+pub fn set_read_strategy(strategy: ReadStrategy) {
+    READ_MMAP.store(strategy == ReadStrategy::Mmap, Ordering::Relaxed);
+}
+
+// .dat を読むコマンドは全部これでアーカイブを開く (extract と cat の --mmap は --read-strategy mmap と同じ)
+pub fn open_reader(archive: impl AsRef<OsStr>, mmap: bool) -> Result<mayakashi::MarReader, MarError> {
+    if mmap || READ_MMAP.load(Ordering::Relaxed) {
+        return mayakashi::MarReader::open_mmap(archive);
+    }
+    mayakashi::MarReader::open(archive)
+}
+
+// create と recompress の --level の既定値
+pub const DEFAULT_ZSTD_LEVEL: i32 = 19;
+
+// zstd が受け付けるレベル (負の値は速いモード) でなければ弾く
+pub fn parse_zstd_level(s: &str) -> Result<i32, String> {
+    let level: i32 = s.parse().map_err(|_| format!("invalid number: {}", s))?;
+    let range = zstd::compression_level_range();
+    if !range.contains(&level) {
+        return Err(format!("zstd level must be between {} and {}", range.start(), range.end()));
+    }
+    Ok(level)
+}
+
+// 大きなファイルを1回だけ舐める時に、ページキャッシュを汚さないようにするためのヒント
+#[derive(Clone, Copy, PartialEq, ValueEnum)]
+pub enum Fadvise {
+    Normal,
+    Sequential,
+    Dontneed,
+}
+
+// Linux 以外では何もしない
+#[cfg(target_os = "linux")]
+pub fn fadvise(file: &std::fs::File, offset: u64, len: u64, advice: Fadvise) {
+    use std::os::unix::io::AsRawFd;
+
+    let advice = match advice {
+        Fadvise::Normal => libc::POSIX_FADV_NORMAL,
+        Fadvise::Sequential => libc::POSIX_FADV_SEQUENTIAL,
+        Fadvise::Dontneed => libc::POSIX_FADV_DONTNEED,
+    };
+    // ただのヒントなので失敗しても気にしない
+    unsafe {
+        libc::posix_fadvise(file.as_raw_fd(), offset as libc::off_t, len as libc::off_t, advice);
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fadvise(_file: &std::fs::File, _offset: u64, _len: u64, _advice: Fadvise) {}
@@ -0,0 +1,213 @@
+use std::{collections::HashMap, fs::File, io::{Seek, Write}, path::PathBuf};
+
+use clap::Parser;
+use sha2::Digest;
+
+use crate::{cmd::{parse_zstd_level, Codec, DEFAULT_ZSTD_LEVEL}, error::MarError, format::{self, body, index_file}, proto::{self, CompressedMethod}};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Archive to read (the name given to `create --output`)
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Archive to write
+    #[arg(short, long)]
+    output: PathBuf,
+
+    #[arg(long, value_enum, default_value_t = Codec::Zstd)]
+    codec: Codec,
+
+    /// Compression level for --codec (zstd: its level range, default 19; lz4: 1..=12, default 12; brotli: 0..=11, default 11)
+    #[arg(long, allow_hyphen_values = true)]
+    level: Option<i32>,
+}
+
+#[derive(Clone)]
+struct RecompressedBody {
+    chunks: Vec<proto::ChunkInfo>,
+    chunks_crc32: u32,
+    chunks_sha256: Vec<u8>,
+    body_offset: u64,
+    body_size: u64,
+}
+
+pub fn main(args: Args) -> Result<(), MarError> {
+    let input = args.input.into_os_string();
+    let output = args.output.into_os_string();
+    let method: CompressedMethod = args.codec.into();
+    let level = codec_level(args.codec, args.level)?;
+
+    let index = index_file::parse_index_file(&mut format::open_shared(&format::idx_file_path(&input))?)?;
+
+    let mut dats = HashMap::new();
+    let mut outdat = format::create_exclusive(&format::dat_file_path(&output, 0))?;
+
+    // dedup されていた entry (と同じ solid blob に入っている entry) は書き直した後も同じ body を指すようにする
+    let mut recompressed_bodies = HashMap::<(u32, u64, u64), RecompressedBody>::new();
+    let mut entries = Vec::with_capacity(index.entries.len());
+    let mut before = 0;
+    let mut after = 0;
+    // 圧縮し直したチャンクは辞書を使わないので、新しい index には入れない
+    let dictionary = index.zstd_dictionary;
+
+    for entry in index.entries {
+        // symlink と空のファイルには body が無い。offset は次の body と同じことがあるので、key にせずにそのまま入れる
+        if entry.inline_body.is_empty() && entry.body_size == 0 {
+            entries.push(proto::FileEntry { file_index: 0, body_offset: 0, ..entry });
+            continue;
+        }
+
+        let info = entry.info.as_ref().unwrap();
+        let is_inline = !entry.inline_body.is_empty();
+        let key = (entry.file_index, entry.body_offset, entry.body_size);
+
+        let (recompressed, inline_body) = match recompressed_bodies.get(&key) {
+            Some(recompressed) if !is_inline => (recompressed.clone(), Vec::new()),
+            _ => {
+                let compressed = if is_inline {
+                    entry.inline_body.clone()
+                } else {
+                    let dat = match dats.entry(entry.file_index) {
+                        std::collections::hash_map::Entry::Occupied(e) => e.into_mut(),
+                        std::collections::hash_map::Entry::Vacant(e) => e.insert(format::open_shared(&format::dat_file_path(&input, entry.file_index))?),
+                    };
+                    body::read_compressed_body(dat, &entry)?
+                };
+                check_checksums(&entry, &compressed, &dictionary)?;
+                let (chunks, new_body) = recompress_body(info, &compressed, &dictionary, method, level)?;
+                // 圧縮し直したものが元に戻るか確認する
+                let recompressed_entry = proto::FileEntry {
+                    info: Some(proto::FileInfo { chunks: chunks.clone(), ..info.clone() }),
+                    ..entry.clone()
+                };
+                check_checksums(&recompressed_entry, &new_body, &[])?;
+                before += compressed.len() as u64;
+                after += new_body.len() as u64;
+                println!("{} ({} -> {} bytes)", info.path, compressed.len(), new_body.len());
+
+                let body_offset = if is_inline {
+                    0
+                } else {
+                    let offset = outdat.seek(std::io::SeekFrom::End(0))?;
+                    outdat.write_all(&new_body)?;
+                    offset
+                };
+                let recompressed = RecompressedBody {
+                    chunks,
+                    chunks_crc32: crc32fast::hash(&new_body),
+                    chunks_sha256: sha2::Sha256::digest(&new_body).to_vec(),
+                    body_offset,
+                    body_size: new_body.len() as u64,
+                };
+                if is_inline {
+                    (recompressed, new_body)
+                } else {
+                    recompressed_bodies.insert(key, recompressed.clone());
+                    (recompressed, Vec::new())
+                }
+            },
+        };
+
+        let info = entry.info.unwrap();
+        entries.push(proto::FileEntry {
+            info: Some(proto::FileInfo {
+                chunks: recompressed.chunks,
+                chunks_crc32: recompressed.chunks_crc32,
+                chunks_sha256: recompressed.chunks_sha256,
+                ..info
+            }),
+            file_index: 0,
+            body_offset: recompressed.body_offset,
+            body_size: recompressed.body_size,
+            inline_body,
+            in_solid_blob: entry.in_solid_blob,
+            solid_offset: entry.solid_offset,
+            solid_length: entry.solid_length,
+        });
+    }
+
+    // 同じ body を指す entry は最初の1つしか上で確かめていないので、書いたものを全部読み直して確かめる
+    for entry in &entries {
+        verify_output(&mut outdat, entry)?;
+    }
+
+    // 中身は変わらないが、古いアーカイブには無いので計算し直す
+    let index = proto::FileIndexFile {
+        content_sha256: index_file::content_sha256(&entries),
+        entries,
+        directories: index.directories,
+        zstd_dictionary: Vec::new(),
+    };
+    let mut outidx = format::create_exclusive(&format::idx_file_path(&output))?;
+    index_file::write_index_file(&mut outidx, &index, CompressedMethod::Zstandard)?;
+
+    println!("{} -> {} bytes", before, after);
+    Ok(())
+}
+
+// チャンクの区切りはそのままに、各チャンクを指定された方式で圧縮し直す
+// --level を選んだ codec の範囲で確かめる (範囲外を黙って丸めない)
+fn codec_level(codec: Codec, level: Option<i32>) -> Result<i32, MarError> {
+    let invalid = |reason: String| MarError::Failed { reason: format!("--level {}: {}", level.unwrap_or_default(), reason) };
+    return match (codec, level) {
+        (Codec::Zstd, None) => Ok(DEFAULT_ZSTD_LEVEL),
+        (Codec::Zstd, Some(level)) => parse_zstd_level(&level.to_string()).map_err(invalid),
+        (Codec::Lz4, None) => Ok(12),
+        (Codec::Lz4, Some(level)) if (1..=12).contains(&level) => Ok(level),
+        (Codec::Lz4, Some(_)) => Err(invalid("lz4 level must be between 1 and 12".to_string())),
+        (Codec::Brotli, None) => Ok(11),
+        (Codec::Brotli, Some(level)) if (0..=11).contains(&level) => Ok(level),
+        (Codec::Brotli, Some(_)) => Err(invalid("brotli level must be between 0 and 11".to_string())),
+        (Codec::Passthrough, None) => Ok(0),
+        (Codec::Passthrough, Some(_)) => Err(invalid("passthrough has no level".to_string())),
+    };
+}
+
+fn recompress_body(info: &proto::FileInfo, compressed: &[u8], dictionary: &[u8], method: CompressedMethod, level: i32) -> Result<(Vec<proto::ChunkInfo>, Vec<u8>), MarError> {
+    let mut chunks = Vec::with_capacity(info.chunks.len());
+    let mut recompressed = Vec::new();
+
+    let mut offset = 0;
+    for chunk in &info.chunks {
+        let end = offset + chunk.compressed_length as usize;
+        let src = compressed.get(offset..end).ok_or_else(|| MarError::Corrupt { detail: format!("{}: chunk runs past the end of the body", info.path) })?;
+        offset = end;
+
+        let original = body::decode_chunk(chunk, src, dictionary)?;
+        let new_compressed = match method {
+            CompressedMethod::Zstandard => zstd::encode_all(&original[..], level)?,
+            CompressedMethod::Lz4 => lz4::block::compress(&original, Some(lz4::block::CompressionMode::HIGHCOMPRESSION(level)), false)?,
+            CompressedMethod::Brotli => body::encode_brotli(&original, level as u32)?,
+            CompressedMethod::Passthrough => original.clone(),
+        };
+        // 縮まなかったらパススルー
+        let (compressed_method, new_compressed) = if new_compressed.len() < original.len() {
+            (method, new_compressed)
+        } else {
+            (CompressedMethod::Passthrough, original)
+        };
+        chunks.push(proto::ChunkInfo {
+            compressed_length: new_compressed.len() as u32,
+            original_length: chunk.original_length,
+            compressed_method: compressed_method as i32,
+            using_dictionary: false,
+        });
+        recompressed.extend_from_slice(&new_compressed);
+    }
+
+    Ok((chunks, recompressed))
+}
+
+// body を展開して entry の original_crc32/original_sha256 と一致するか確認する
+fn check_checksums(entry: &proto::FileEntry, body: &[u8], dictionary: &[u8]) -> Result<(), MarError> {
+    let info = entry.info.as_ref().unwrap();
+    let decoded = body::decode_entry_body(entry, body, dictionary)?;
+    body::verify_original(info, &decoded)
+}
+
+// 書き出した entry の body (inline か outdat) を展開して、元のファイルに戻るか確認する
+fn verify_output(outdat: &mut File, entry: &proto::FileEntry) -> Result<(), MarError> {
+    let body = body::read_compressed_body(outdat, entry)?;
+    check_checksums(entry, &body, &[])
+}
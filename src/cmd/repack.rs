@@ -0,0 +1,175 @@
+use std::{collections::HashMap, io::Write, path::PathBuf};
+
+use clap::Parser;
+use sha2::Digest;
+
+use crate::{cmd::create::SOLID_BLOB_SIZE, error::MarError, format::{self, body, index_file}, proto::{self, CompressedMethod}};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Archive to read (the name given to `create --output`)
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Archive to write
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Store every file up to 8MiB in solid blobs, like `create --solid-under 8MiB`
+    #[arg(long, required = true)]
+    solid: bool,
+}
+
+// まだ書いていない solid blob
+#[derive(Default)]
+struct PendingBlob {
+    data: Vec<u8>,
+    // (entries の添字, blob の中の offset, 長さ)
+    members: Vec<(usize, u64, u64)>,
+}
+
+pub fn main(args: Args) -> Result<(), MarError> {
+    let input = args.input.into_os_string();
+    let output = args.output.into_os_string();
+    // 今のところ solid にする以外の repack はないが、何をするかはコマンドラインに書かせる
+    let _ = args.solid;
+
+    let index = index_file::parse_index_file(&mut format::open_shared(&format::idx_file_path(&input))?)?;
+
+    let mut dats = format::DatFiles::new(&input);
+    let mut outdat = format::create_exclusive(&format::dat_file_path(&output, 0))?;
+    let mut outdat_len = 0;
+
+    // 元の archive で同じ中身を指していた entry (dedup) は、書き直した後も同じ場所を指すようにする
+    let mut placed = HashMap::<(u32, u64, u64, u64), usize>::new();
+    let mut aliases = Vec::new();
+    let mut before = 0;
+    let mut seen_bodies = std::collections::HashSet::new();
+    // solid blob の中身は隣り合った entry で同じ body を読むので、直前に読んだものを取っておく
+    let mut last_body: Option<((u32, u64), Vec<u8>)> = None;
+
+    let mut entries = Vec::with_capacity(index.entries.len());
+    let mut pending = PendingBlob::default();
+
+    for entry in index.entries {
+        // inline と空のファイルはそのまま
+        if !entry.inline_body.is_empty() || entry.body_size == 0 {
+            entries.push(entry);
+            continue;
+        }
+
+        let key = (entry.file_index, entry.body_offset, entry.solid_offset, entry.solid_length);
+        if let Some(&canonical) = placed.get(&key) {
+            aliases.push((entries.len(), canonical));
+            entries.push(entry);
+            continue;
+        }
+        placed.insert(key, entries.len());
+
+        let body_key = (entry.file_index, entry.body_offset);
+        if seen_bodies.insert(body_key) {
+            before += entry.body_size;
+        }
+        let compressed = match last_body.take() {
+            Some((k, compressed)) if k == body_key => compressed,
+            _ => body::read_compressed_body(dats.get(entry.file_index)?, &entry)?,
+        };
+        let decoded = body::decode_entry_body(&entry, &compressed, &index.zstd_dictionary)?;
+        body::verify_original(entry.info.as_ref().unwrap(), &decoded)?;
+
+        if decoded.len() as u64 > SOLID_BLOB_SIZE {
+            // blob に入らない大きさのファイルは元の body をそのまま写す
+            outdat.write_all(&compressed)?;
+            entries.push(proto::FileEntry {
+                file_index: 0,
+                body_offset: outdat_len,
+                ..entry
+            });
+            outdat_len += compressed.len() as u64;
+            last_body = Some((body_key, compressed));
+            continue;
+        }
+        last_body = Some((body_key, compressed));
+
+        if !pending.data.is_empty() && pending.data.len() as u64 + decoded.len() as u64 > SOLID_BLOB_SIZE {
+            outdat_len += write_blob(&mut outdat, outdat_len, std::mem::take(&mut pending), &mut entries)?;
+        }
+        pending.members.push((entries.len(), pending.data.len() as u64, decoded.len() as u64));
+        pending.data.extend_from_slice(&decoded);
+        entries.push(entry);
+    }
+    if !pending.data.is_empty() {
+        outdat_len += write_blob(&mut outdat, outdat_len, pending, &mut entries)?;
+    }
+
+    for (alias, canonical) in aliases {
+        let canonical = entries[canonical].clone();
+        let entry = &mut entries[alias];
+        let info = entry.info.take().unwrap();
+        let canonical_info = canonical.info.unwrap();
+        *entry = proto::FileEntry {
+            info: Some(proto::FileInfo {
+                chunks: canonical_info.chunks,
+                chunks_crc32: canonical_info.chunks_crc32,
+                chunks_sha256: canonical_info.chunks_sha256,
+                ..info
+            }),
+            ..canonical
+        };
+    }
+
+    let index = proto::FileIndexFile {
+        content_sha256: index_file::content_sha256(&entries),
+        entries,
+        directories: index.directories,
+        // inline の entry と写した body は元の辞書を使っていることがある
+        zstd_dictionary: index.zstd_dictionary,
+    };
+    let mut outidx = format::create_exclusive(&format::idx_file_path(&output))?;
+    index_file::write_index_file(&mut outidx, &index, CompressedMethod::Zstandard)?;
+
+    let saved = before as i64 - outdat_len as i64;
+    println!("{} -> {} bytes ({:.1}% smaller)", before, outdat_len, if before == 0 { 0.0 } else { saved as f64 * 100.0 / before as f64 });
+    Ok(())
+}
+
+// blob を丸ごと1つの zstd フレームにして書き、members の entry をその blob を指すように書き換える。書いたバイト数を返す
+fn write_blob(outdat: &mut impl Write, offset: u64, blob: PendingBlob, entries: &mut [proto::FileEntry]) -> Result<u64, MarError> {
+    let compressed = zstd::encode_all(&blob.data[..], 22)?;
+    let (method, compressed) = if compressed.len() < blob.data.len() {
+        (CompressedMethod::Zstandard, compressed)
+    } else {
+        (CompressedMethod::Passthrough, blob.data.clone())
+    };
+    let chunk = proto::ChunkInfo {
+        compressed_length: compressed.len() as u32,
+        original_length: blob.data.len() as u32,
+        compressed_method: method as i32,
+        using_dictionary: false,
+    };
+
+    // 書く前に、展開して元に戻ることを確かめる
+    if body::decode_chunk(&chunk, &compressed, &[])? != blob.data {
+        return Err(MarError::Corrupt { detail: "repacked blob doesn't decode to its input".to_string() });
+    }
+
+    let chunks_crc32 = crc32fast::hash(&compressed);
+    let chunks_sha256 = sha2::Sha256::digest(&compressed).to_vec();
+    for (i, solid_offset, solid_length) in blob.members {
+        let entry = &mut entries[i];
+        let info = entry.info.as_mut().unwrap();
+        info.chunks = vec![chunk.clone()];
+        info.chunks_crc32 = chunks_crc32;
+        // --no-integrity-hash で作られたアーカイブなら SHA-256 は入れないまま
+        info.chunks_sha256 = if info.original_sha256.is_empty() { Vec::new() } else { chunks_sha256.clone() };
+        entry.file_index = 0;
+        entry.body_offset = offset;
+        entry.body_size = compressed.len() as u64;
+        entry.in_solid_blob = true;
+        entry.solid_offset = solid_offset;
+        entry.solid_length = solid_length;
+    }
+
+    outdat.write_all(&compressed)?;
+    Ok(compressed.len() as u64)
+}
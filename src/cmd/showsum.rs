@@ -2,6 +2,8 @@ use std::path::PathBuf;
 
 use clap::Parser;
 
+use crate::error::MarError;
+
 
 #[derive(Parser)]
 #[command(name = "MAR Maker")]
@@ -10,9 +12,9 @@ pub struct Args {
     input: PathBuf,
 }
 
-pub fn main(args: Args) {
-    let mut file = std::fs::File::open(args.input).unwrap();
-    let file = crate::format::index_file::parse_index_file(&mut file);
+pub fn main(args: Args) -> Result<(), MarError> {
+    let mut file = crate::format::open_shared(args.input.as_os_str())?;
+    let file = crate::format::index_file::parse_index_file(&mut file)?;
     for entry in file.entries {
         let info = entry.info.unwrap();
         let sha256 = info.original_sha256;
@@ -23,4 +25,5 @@ pub fn main(args: Args) {
         }
         println!("{}\t{}", hex, info.path);
     }
+    Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,50 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use sha2::Digest;
+
+use crate::{error::MarError, proto};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Archive to check (the name given to `create --output`)
+    #[arg(short, long)]
+    input: PathBuf,
+}
+
+pub fn main(args: Args) -> Result<(), MarError> {
+    let reader = crate::cmd::open_reader(&args.input, false)?;
+
+    let mut failed = 0;
+    for entry in reader.entries() {
+        let info = entry.info.as_ref().unwrap();
+        match check_entry(&reader, entry) {
+            Ok(()) => println!("OK {}", info.path),
+            Err(e) => {
+                println!("FAIL {}: {}", info.path, e);
+                failed += 1;
+            },
+        }
+    }
+
+    if failed > 0 {
+        return Err(MarError::VerifyFailed { failed, total: reader.entries().len() });
+    }
+    println!("all {} files OK", reader.entries().len());
+    Ok(())
+}
+
+// create と同じく、圧縮されたままの body の CRC32/SHA-256 と、展開したものの CRC32/SHA-256 を確かめる
+fn check_entry(reader: &mayakashi::MarReader, entry: &proto::FileEntry) -> Result<(), MarError> {
+    let info = entry.info.as_ref().unwrap();
+    let compressed = reader.read_compressed(entry)?;
+    if crc32fast::hash(&compressed) != info.chunks_crc32 {
+        return Err(MarError::Corrupt { detail: "chunks_crc32 mismatch".to_string() });
+    }
+    // --no-integrity-hash で作られたアーカイブには SHA-256 が入っていない
+    if !info.chunks_sha256.is_empty() && sha2::Sha256::digest(&compressed).to_vec() != info.chunks_sha256 {
+        return Err(MarError::Corrupt { detail: "chunks_sha256 mismatch".to_string() });
+    }
+    reader.read_file(entry)?;
+    Ok(())
+}
@@ -0,0 +1,117 @@
+use std::{collections::HashMap, io::Read, path::PathBuf};
+
+use clap::Parser;
+use sha2::Digest;
+
+use crate::{cmd::create::walk_dir, error::MarError, format::{self, body, index_file}};
+
+#[derive(Parser)]
+pub struct Args {
+    /// .mar.idx file to compare against
+    #[arg(long)]
+    index: PathBuf,
+
+    /// Directory to check
+    #[arg(long)]
+    dir: PathBuf,
+}
+
+pub fn main(args: Args) -> Result<(), MarError> {
+    let index = index_file::parse_index_file(&mut format::open_shared(args.index.as_os_str())?)?;
+
+    let (files, _) = walk_dir(&args.dir);
+    let mut on_disk = HashMap::new();
+    for file in files {
+        // create と同じく .DS_Store は無視する
+        if file.path.file_name().unwrap() == ".DS_Store" {
+            continue;
+        }
+        let relative_path = file.path.strip_prefix(&args.dir).unwrap().to_path_buf();
+        on_disk.insert(relative_path, file);
+    }
+
+    let mut differences = 0;
+    for entry in &index.entries {
+        let info = entry.info.as_ref().unwrap();
+        // 古いアーカイブは create に渡された --input の書き方によって先頭に / が付いていたりいなかったりする
+        let file = match on_disk.remove(&format::relative_path(&info.path, &info.raw_path)) {
+            Some(file) => file,
+            None => {
+                println!("D {}", info.path);
+                differences += 1;
+                continue;
+            },
+        };
+
+        let mut reasons = Vec::new();
+        let is_link_entry = !info.symlink_target.is_empty();
+        if is_link_entry {
+            // symlink の entry には body が無いので、リンク先を比べる
+            match std::fs::read_link(&file.path) {
+                Ok(target) if target.as_os_str() == format::decode_path(&info.symlink_target, &info.raw_symlink_target) => {},
+                Ok(_) => reasons.push("target"),
+                Err(_) => reasons.push("type"),
+            }
+        } else {
+            // --dereference で作ったアーカイブなら、symlink の先と比べる
+            let size = match file.symlink {
+                true => std::fs::metadata(&file.path).map(|m| m.len()).ok(),
+                false => Some(file.size),
+            };
+            match size {
+                Some(size) if size == body::original_size(entry) => {
+                    let (crc32, sha256) = hash_file(&file.path)?;
+                    if crc32 != info.original_crc32 || (!info.original_sha256.is_empty() && sha256 != info.original_sha256) {
+                        reasons.push("content");
+                    }
+                },
+                Some(_) => reasons.push("size"),
+                None => reasons.push("type"),
+            }
+        }
+        let metadata = match is_link_entry {
+            true => std::fs::symlink_metadata(&file.path),
+            false => std::fs::metadata(&file.path),
+        };
+        if let Ok(metadata) = metadata {
+            let modified_time = prost_types::Timestamp::from(metadata.modified()?);
+            if Some(modified_time) != info.modified_time {
+                reasons.push("mtime");
+            }
+        }
+
+        if !reasons.is_empty() {
+            println!("M {} ({})", info.path, reasons.join(", "));
+            differences += 1;
+        }
+    }
+
+    let mut added = on_disk.keys().collect::<Vec<_>>();
+    added.sort();
+    for path in added {
+        println!("A {}", path.display());
+        differences += 1;
+    }
+
+    if differences > 0 {
+        return Err(MarError::TreeDiffers { differences });
+    }
+    println!("{} matches the index ({} files)", args.dir.display(), index.entries.len());
+    Ok(())
+}
+
+fn hash_file(path: &PathBuf) -> Result<(u32, Vec<u8>), MarError> {
+    let mut crc32_hasher = crc32fast::Hasher::new();
+    let mut sha256_hasher = sha2::Sha256::new();
+    let mut reader = std::io::BufReader::new(std::fs::File::open(path)?);
+    let mut buf = [0; 32768];
+    loop {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        crc32_hasher.update(&buf[..n]);
+        sha256_hasher.update(&buf[..n]);
+    }
+    Ok((crc32_hasher.finalize(), sha256_hasher.finalize().to_vec()))
+}
@@ -0,0 +1,52 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+
+use crate::{error::MarError, format::{self, body, index_file}, proto::CompressedMethod};
+
+#[derive(Parser)]
+pub struct Args {
+    /// Archive to read (the name given to `create --output`)
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Byte offset in the .dat file
+    #[arg(long)]
+    offset: u64,
+
+    /// Which .dat file the offset is in (0 for .mar.dat, N for .mar.N.dat)
+    #[arg(long, default_value_t = 0)]
+    file_index: u32,
+}
+
+pub fn main(args: Args) -> Result<(), MarError> {
+    let index = index_file::parse_index_file(&mut format::open_shared(&format::idx_file_path(args.input.as_os_str()))?)?;
+
+    // entry は offset 順に並んでいないので全部見る (dedup されていると複数見つかることもある)
+    let mut found = false;
+    for entry in &index.entries {
+        if !entry.inline_body.is_empty() || entry.file_index != args.file_index {
+            continue;
+        }
+        if args.offset < entry.body_offset || args.offset >= entry.body_offset + entry.body_size {
+            continue;
+        }
+        found = true;
+
+        let info = entry.info.as_ref().unwrap();
+        println!("{} (body {}..{})", info.path, entry.body_offset, entry.body_offset + entry.body_size);
+        for (i, (chunk, offset)) in info.chunks.iter().zip(body::chunk_offsets(info)).enumerate() {
+            let chunk_start = entry.body_offset + offset.compressed_offset;
+            let chunk_end = chunk_start + chunk.compressed_length as u64;
+            let method = CompressedMethod::try_from(chunk.compressed_method).map(|m| m.as_str_name()).unwrap_or("UNKNOWN");
+            let marker = if chunk_start <= args.offset && args.offset < chunk_end { " <-" } else { "" };
+            let original_end = offset.original_offset + chunk.original_length as u64;
+            println!("  chunk {}: {}..{} {} (file {}..{}, {} -> {} bytes){}", i, chunk_start, chunk_end, method, offset.original_offset, original_end, chunk.original_length, chunk.compressed_length, marker);
+        }
+    }
+
+    if !found {
+        println!("offset {} is unreferenced (not inside any body)", args.offset);
+    }
+    Ok(())
+}
@@ -0,0 +1,44 @@
+use std::{io, path::{Path, PathBuf}};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum MarError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("{path}: {source}")]
+    File { path: String, source: io::Error },
+    #[error("bad magic: {0:?}")]
+    BadMagic([u8; 4]),
+    #[error("unsupported compression method: {0}")]
+    UnsupportedCodec(i32),
+    #[error("corrupt archive: {detail}")]
+    Corrupt { detail: String },
+    #[error("checksum mismatch: {path}")]
+    ChecksumMismatch { path: String },
+    #[error("{path} is locked by another mayakashi process (pass --no-lock to ignore)")]
+    Locked { path: String },
+    #[error("{path}: no such file in the archive")]
+    NotFound { path: String },
+    #[error("{path}: range {start}..{end} is outside of the file ({size} bytes)")]
+    OutOfRange { path: String, start: u64, end: u64, size: u64 },
+    // `..` や絶対パス、途中の symlink で展開先 (or 読み込み元) のディレクトリの外を指しているパス
+    #[error("{}: path escapes the target directory", .0.display())]
+    PathTraversal(PathBuf),
+    // verify で壊れていた entry があった
+    #[error("{failed} of {total} files failed")]
+    VerifyFailed { failed: usize, total: usize },
+    // verify-tree でディレクトリと index が違った
+    #[error("{differences} differences")]
+    TreeDiffers { differences: usize },
+    // create が途中で諦めた時 (--max-entries を超えた、ファイルを取りこぼした、など)
+    #[error("{reason}")]
+    Failed { reason: String },
+}
+
+impl MarError {
+    // I/O エラーにどのファイルで起きたかを付ける。map_err(MarError::at(path)) のように使う
+    pub fn at(path: &Path) -> impl FnOnce(io::Error) -> MarError + '_ {
+        move |source| MarError::File { path: path.display().to_string(), source }
+    }
+}
@@ -0,0 +1,138 @@
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use crate::{error::MarError, proto::{self, CompressedMethod}};
+
+// dictionary は FileIndexFile.zstd_dictionary (using_dictionary のチャンクだけが使う)
+pub fn decode_chunk(chunk: &proto::ChunkInfo, compressed: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, MarError> {
+    let method = CompressedMethod::try_from(chunk.compressed_method).map_err(|_| MarError::UnsupportedCodec(chunk.compressed_method))?;
+    if chunk.using_dictionary && (method != CompressedMethod::Zstandard || dictionary.is_empty()) {
+        return Err(MarError::Corrupt { detail: format!("{:?} chunk uses a dictionary, but the index has none for it", method) });
+    }
+    let decoded = match method {
+        CompressedMethod::Passthrough => compressed.to_vec(),
+        CompressedMethod::Zstandard => decode_zstd(compressed, chunk.original_length as u64, if chunk.using_dictionary { dictionary } else { &[] })?,
+        CompressedMethod::Lz4 => {
+            check_lz4_ratio(compressed.len(), chunk.original_length as u64)?;
+            lz4::block::decompress(compressed, Some(chunk.original_length as i32))?
+        },
+        CompressedMethod::Brotli => decode_brotli(compressed, chunk.original_length as u64)?,
+    };
+    if decoded.len() != chunk.original_length as usize {
+        return Err(MarError::Corrupt { detail: format!("chunk decoded to {} bytes, expected {}", decoded.len(), chunk.original_length) });
+    }
+    Ok(decoded)
+}
+
+// quality は 0..=11 (11 が一番縮む)
+pub fn encode_brotli(src: &[u8], quality: u32) -> std::io::Result<Vec<u8>> {
+    let mut compressed = Vec::with_capacity(src.len() / 2);
+    let mut writer = brotli::CompressorWriter::new(&mut compressed, 4096, quality, 22);
+    writer.write_all(src)?;
+    writer.flush()?;
+    drop(writer);
+    Ok(compressed)
+}
+
+// 宣言された長さは信用できないので、展開前に確保しておくのはこれまで
+const MAX_PREALLOCATE: u64 = 16 * 1024 * 1024;
+
+// LZ4 の block は 1 バイトから高々 255 バイトくらいにしかならないので、それより縮んでいると言っているものは壊れている
+// (lz4::block::decompress は宣言された長さを先に確保するので、その前に弾く)
+const LZ4_MAX_RATIO: u64 = 255;
+
+pub(crate) fn check_lz4_ratio(compressed_length: usize, original_length: u64) -> Result<(), MarError> {
+    if original_length > (compressed_length as u64 + 16) * LZ4_MAX_RATIO {
+        return Err(MarError::Corrupt { detail: format!("lz4 data of {} bytes can't decode to {} bytes", compressed_length, original_length) });
+    }
+    Ok(())
+}
+
+// 壊れたデータで延々と展開し続けないように、期待する長さ + 1 バイトまでしか展開しない (超えたら呼び出し元で弾く)
+pub fn decode_brotli(compressed: &[u8], original_length: u64) -> std::io::Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(original_length.min(MAX_PREALLOCATE) as usize);
+    brotli::Decompressor::new(compressed, 4096).take(original_length + 1).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+// decode_brotli と同じく期待する長さ + 1 バイトまで。dictionary が空なら辞書無し
+pub fn decode_zstd(compressed: &[u8], original_length: u64, dictionary: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut decoded = Vec::with_capacity(original_length.min(MAX_PREALLOCATE) as usize);
+    zstd::Decoder::with_dictionary(compressed, dictionary)?.take(original_length + 1).read_to_end(&mut decoded)?;
+    Ok(decoded)
+}
+
+// .dat (か inline_body) から圧縮されたままの body を読む
+pub fn read_compressed_body(dat: &mut (impl Read + Seek), entry: &proto::FileEntry) -> Result<Vec<u8>, MarError> {
+    if !entry.inline_body.is_empty() {
+        return Ok(entry.inline_body.clone());
+    }
+    dat.seek(SeekFrom::Start(entry.body_offset))?;
+    let mut body = vec![0; entry.body_size as usize];
+    dat.read_exact(&mut body)?;
+    Ok(body)
+}
+
+// チャンクの位置。ChunkInfo には長さしか無いので、それより前のチャンクの長さを足して求める
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ChunkOffset {
+    // body の先頭から
+    pub compressed_offset: u64,
+    // 展開後の先頭から (solid blob なら blob の先頭から)
+    pub original_offset: u64,
+}
+
+pub fn chunk_offsets(info: &proto::FileInfo) -> Vec<ChunkOffset> {
+    let mut offsets = Vec::with_capacity(info.chunks.len());
+    let mut offset = ChunkOffset { compressed_offset: 0, original_offset: 0 };
+    for chunk in &info.chunks {
+        offsets.push(offset);
+        offset.compressed_offset += chunk.compressed_length as u64;
+        offset.original_offset += chunk.original_length as u64;
+    }
+    offsets
+}
+
+// 展開後のファイルの大きさ
+pub fn original_size(entry: &proto::FileEntry) -> u64 {
+    if entry.in_solid_blob {
+        return entry.solid_length;
+    }
+    entry.info.as_ref().unwrap().chunks.iter().map(|c| c.original_length as u64).sum()
+}
+
+pub fn decode_body(info: &proto::FileInfo, body: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, MarError> {
+    let mut decoded = Vec::new();
+    for (chunk, offset) in info.chunks.iter().zip(chunk_offsets(info)) {
+        let start = offset.compressed_offset as usize;
+        let end = start + chunk.compressed_length as usize;
+        let compressed = body.get(start..end).ok_or_else(|| MarError::Corrupt { detail: format!("{}: chunk runs past the end of the body", info.path) })?;
+        decoded.extend_from_slice(&decode_chunk(chunk, compressed, dictionary)?);
+    }
+    Ok(decoded)
+}
+
+// solid blob に入っている entry は blob 全体を展開してから自分の範囲を切り出す
+pub fn decode_entry_body(entry: &proto::FileEntry, body: &[u8], dictionary: &[u8]) -> Result<Vec<u8>, MarError> {
+    let info = entry.info.as_ref().unwrap();
+    let decoded = decode_body(info, body, dictionary)?;
+    if !entry.in_solid_blob {
+        return Ok(decoded);
+    }
+    let start = entry.solid_offset as usize;
+    let end = start + entry.solid_length as usize;
+    decoded.get(start..end).map(|d| d.to_vec()).ok_or_else(|| MarError::Corrupt { detail: format!("{}: solid range is outside of the blob", info.path) })
+}
+
+// 展開したものが original_crc32/original_sha256 と一致するか確認する
+pub fn verify_original(info: &proto::FileInfo, decoded: &[u8]) -> Result<(), MarError> {
+    use sha2::Digest;
+
+    if crc32fast::hash(decoded) != info.original_crc32 {
+        return Err(MarError::ChecksumMismatch { path: info.path.clone() });
+    }
+    // --no-integrity-hash で作られたアーカイブには SHA-256 が入っていない
+    if !info.original_sha256.is_empty() && sha2::Sha256::digest(decoded).to_vec() != info.original_sha256 {
+        return Err(MarError::ChecksumMismatch { path: info.path.clone() });
+    }
+    Ok(())
+}
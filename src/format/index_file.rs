@@ -1,35 +1,141 @@
-use std::io::Read;
+use std::io::{Read, Write};
 
 use prost::Message;
 
-use crate::proto;
+use crate::{error::MarError, proto::{self, CompressedMethod}};
 
 const INDEX_MAGIC: &[u8; 4] = b"MARI";
+const INDEX_MAGIC_WITH_METHOD: &[u8; 4] = b"MAR2";
 
-pub fn parse_index_file(input: &mut impl Read) -> proto::FileIndexFile {
-    // first 4 bytes: INDEX_MAGIC
+// 壊れた (or 悪意のある) index で巨大なメモリ確保をしないように、宣言された長さはこれ以下でないと受け付けない
+pub const DEFAULT_MAX_INDEX_SIZE: u32 = 1024 * 1024 * 1024;
+
+pub fn parse_index_file(input: &mut impl Read) -> Result<proto::FileIndexFile, MarError> {
+    return parse_index_file_with_limit(input, DEFAULT_MAX_INDEX_SIZE);
+}
+
+pub fn parse_index_file_with_limit(input: &mut impl Read, max_size: u32) -> Result<proto::FileIndexFile, MarError> {
+    // first 4 bytes: INDEX_MAGIC or INDEX_MAGIC_WITH_METHOD
+    // (INDEX_MAGIC_WITH_METHOD only) next 1 byte: compressed method
     // next 4 bytes: compressed length (big-endian)
     // next 4 bytes: raw length (big-endian)
     // (data)
 
     let mut magic = [0; 4];
-    input.read_exact(&mut magic).unwrap();
-    assert_eq!(&magic, INDEX_MAGIC);
+    input.read_exact(&mut magic)?;
+    let method = if &magic == INDEX_MAGIC {
+        // 古い形式は zstd 固定
+        CompressedMethod::Zstandard
+    } else if &magic == INDEX_MAGIC_WITH_METHOD {
+        let mut method = [0; 1];
+        input.read_exact(&mut method)?;
+        CompressedMethod::try_from(method[0] as i32).map_err(|_| MarError::UnsupportedCodec(method[0] as i32))?
+    } else {
+        return Err(MarError::BadMagic(magic));
+    };
 
     let mut compressed_len = [0; 4];
-    input.read_exact(&mut compressed_len).unwrap();
+    input.read_exact(&mut compressed_len)?;
     let compressed_len = u32::from_be_bytes(compressed_len);
 
     let mut raw_len = [0; 4];
-    input.read_exact(&mut raw_len).unwrap();
+    input.read_exact(&mut raw_len)?;
     let raw_len = u32::from_be_bytes(raw_len);
 
-    let mut compressed = Vec::with_capacity(compressed_len as usize);
+    if compressed_len > max_size || raw_len > max_size {
+        return Err(MarError::Corrupt { detail: format!("index is too large (compressed {} bytes, raw {} bytes, limit {} bytes)", compressed_len, raw_len, max_size) });
+    }
+
+    // 宣言された長さを信用して先に確保はしない
+    let mut compressed = Vec::new();
     let mut l = input.take(compressed_len as u64);
-    l.read_to_end(&mut compressed).unwrap();
+    l.read_to_end(&mut compressed)?;
+
+    if compressed.len() != compressed_len as usize {
+        return Err(MarError::Corrupt { detail: format!("index is truncated ({} of {} bytes)", compressed.len(), compressed_len) });
+    }
+
+    let raw = match method {
+        // zstd bomb 対策で raw_len + 1 バイトまでしか展開しない (超えたら下で弾く)
+        CompressedMethod::Zstandard => super::body::decode_zstd(&compressed, raw_len as u64, &[])?,
+        CompressedMethod::Lz4 => {
+            super::body::check_lz4_ratio(compressed.len(), raw_len as u64)?;
+            lz4::block::decompress(&compressed, Some(raw_len as i32))?
+        },
+        CompressedMethod::Brotli => super::body::decode_brotli(&compressed, raw_len as u64)?,
+        CompressedMethod::Passthrough => compressed,
+    };
+    if raw.len() != raw_len as usize {
+        return Err(MarError::Corrupt { detail: format!("index raw length mismatch (expected {}, got {})", raw_len, raw.len()) });
+    }
+
+    let index = proto::FileIndexFile::decode(&raw[..]).map_err(|e| MarError::Corrupt { detail: e.to_string() })?;
+    // 読む側は entry に info があるものとして扱うので、ここで弾いておく
+    if let Some(i) = index.entries.iter().position(|e| e.info.is_none()) {
+        return Err(MarError::Corrupt { detail: format!("entry {} has no file info", i) });
+    }
+    return Ok(index);
+}
+
+// アーカイブ全体の中身を表すハッシュ。圧縮の設定に関係なく、同じファイル群なら同じ値になる
+// entries を path の (UTF-8 の) バイト順に並べて、それぞれについて
+//   path のバイト長 (u32 big-endian) || path || original_sha256 (32 bytes)
+// を繋げたものの SHA-256。original_sha256 が無い entry があれば空
+pub fn content_sha256(entries: &[proto::FileEntry]) -> Vec<u8> {
+    use sha2::Digest;
+
+    let mut infos = entries.iter().map(|e| e.info.as_ref().unwrap()).collect::<Vec<_>>();
+    if infos.iter().any(|info| info.original_sha256.len() != 32) {
+        return Vec::new();
+    }
+    infos.sort_by(|a, b| a.path.as_bytes().cmp(b.path.as_bytes()).then_with(|| a.raw_path.cmp(&b.raw_path)));
+
+    let mut hasher = sha2::Sha256::new();
+    for info in infos {
+        hasher.update((info.path.len() as u32).to_be_bytes());
+        hasher.update(info.path.as_bytes());
+        hasher.update(&info.original_sha256);
+        // symlink は中身が空なので、リンク先で区別する (symlink の無いアーカイブでは今まで通りの値になる)
+        if !info.symlink_target.is_empty() {
+            let target = if info.raw_symlink_target.is_empty() { info.symlink_target.as_bytes() } else { &info.raw_symlink_target[..] };
+            hasher.update((target.len() as u32).to_be_bytes());
+            hasher.update(target);
+        }
+        // UTF-8 でないパスは置き換え文字にすると区別できなくなるので、元のバイト列も入れる
+        if !info.raw_path.is_empty() {
+            hasher.update((info.raw_path.len() as u32).to_be_bytes());
+            hasher.update(&info.raw_path);
+        }
+    }
+    return hasher.finalize().to_vec();
+}
+
+// index は小さいので、指定が無ければ zstd の一番高いレベルで圧縮する
+pub const DEFAULT_INDEX_ZSTD_LEVEL: i32 = 22;
+
+pub fn write_index_file(output: &mut impl Write, index_file: &proto::FileIndexFile, method: CompressedMethod) -> Result<(), MarError> {
+    return write_index_file_with_level(output, index_file, method, DEFAULT_INDEX_ZSTD_LEVEL);
+}
 
-    let raw = zstd::decode_all(&compressed[..]).unwrap();
-    assert_eq!(raw.len(), raw_len as usize);
+// zstd_level は method が Zstandard の時だけ使う
+pub fn write_index_file_with_level(output: &mut impl Write, index_file: &proto::FileIndexFile, method: CompressedMethod, zstd_level: i32) -> Result<(), MarError> {
+    let raw = index_file.encode_to_vec();
+    let compressed = match method {
+        CompressedMethod::Zstandard => zstd::encode_all(&raw[..], zstd_level)?,
+        CompressedMethod::Lz4 => lz4::block::compress(&raw, Some(lz4::block::CompressionMode::HIGHCOMPRESSION(12)), false)?,
+        CompressedMethod::Brotli => super::body::encode_brotli(&raw, 11)?,
+        CompressedMethod::Passthrough => raw.clone(),
+    };
 
-    return proto::FileIndexFile::decode(&raw[..]).unwrap();
-}
\ No newline at end of file
+    // zstd の時は古いリーダーでも読めるように INDEX_MAGIC で書く
+    if method == CompressedMethod::Zstandard {
+        output.write_all(INDEX_MAGIC)?;
+    } else {
+        output.write_all(INDEX_MAGIC_WITH_METHOD)?;
+        output.write_all(&[method as u8])?;
+    }
+    output.write_all(&(compressed.len() as u32).to_be_bytes())?;
+    output.write_all(&(raw.len() as u32).to_be_bytes())?;
+    output.write_all(&compressed)?;
+    Ok(())
+}
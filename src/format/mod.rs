@@ -1 +1,141 @@
-pub mod index_file;
\ No newline at end of file
+use std::{collections::HashMap, ffi::{OsStr, OsString}, fs::File, path::{Component, Path, PathBuf}, sync::atomic::{AtomicBool, Ordering}};
+
+use crate::error::MarError;
+
+pub mod index_file;
+pub mod body;
+
+pub fn idx_file_path(archive: &OsStr) -> OsString {
+    let mut path = OsString::from(archive);
+    path.push(".mar.idx");
+    return path;
+}
+
+// <archive>.mar.dat, <archive>.mar.1.dat, <archive>.mar.2.dat, ...
+pub fn dat_file_path(archive: &OsStr, file_index: u32) -> OsString {
+    let mut path = OsString::from(archive);
+    if file_index == 0 {
+        path.push(".mar.dat");
+    } else {
+        path.push(format!(".mar.{}.dat", file_index));
+    }
+    return path;
+}
+
+// --index-only-output で書く、.dat 無しで配れる一覧
+pub fn catalog_file_path(archive: &OsStr) -> OsString {
+    let mut path = OsString::from(archive);
+    path.push(".mar.catalog.idx");
+    return path;
+}
+
+// index に書くパス。UTF-8 でない時は path に置き換え文字入りのものを、raw_path に元のバイト列を入れる
+// (Windows のパスは UTF-16 なので、そのまま文字列にする)
+pub fn encode_path(path: &OsStr) -> (String, Vec<u8>) {
+    match path.to_str() {
+        Some(path) => (path.to_string(), Vec::new()),
+        #[cfg(unix)]
+        None => {
+            use std::os::unix::ffi::OsStrExt;
+            (path.to_string_lossy().into_owned(), path.as_bytes().to_vec())
+        },
+        #[cfg(not(unix))]
+        None => (path.to_string_lossy().into_owned(), Vec::new()),
+    }
+}
+
+// encode_path の逆。raw_path は Unix でしか意味が無いので、他では path を使う
+pub fn decode_path(path: &str, raw_path: &[u8]) -> OsString {
+    #[cfg(unix)]
+    if !raw_path.is_empty() {
+        use std::os::unix::ffi::OsStrExt;
+        return OsStr::from_bytes(raw_path).to_os_string();
+    }
+    #[cfg(not(unix))]
+    let _ = raw_path;
+    OsString::from(path)
+}
+
+// index のパス ("/foo/bar"、古い版が Windows で作ったものなら "\foo\bar") を要素に分ける
+pub fn path_components(path: &str, raw_path: &[u8]) -> Vec<OsString> {
+    #[cfg(unix)]
+    if !raw_path.is_empty() {
+        use std::os::unix::ffi::OsStrExt;
+        return raw_path.split(|&b| b == b'/' || b == b'\\').filter(|p| !p.is_empty()).map(|p| OsStr::from_bytes(p).to_os_string()).collect();
+    }
+    #[cfg(not(unix))]
+    let _ = raw_path;
+    path.split(['/', '\\']).filter(|p| !p.is_empty()).map(OsString::from).collect()
+}
+
+// input からの相対パスにする (先頭の区切りは付けない)
+pub fn relative_path(path: &str, raw_path: &[u8]) -> PathBuf {
+    path_components(path, raw_path).into_iter().collect()
+}
+
+// relative_path と同じだが、`..` などで base の外に出るパスはエラーにする (アーカイブの中身は信用しない)
+pub fn contained_relative_path(path: &str, raw_path: &[u8]) -> Result<PathBuf, MarError> {
+    let mut relative = PathBuf::new();
+    for part in path_components(path, raw_path) {
+        match Path::new(&part).components().next() {
+            Some(Component::Normal(_)) => relative.push(part),
+            _ => return Err(MarError::PathTraversal(PathBuf::from(path))),
+        }
+    }
+    Ok(relative)
+}
+
+// --no-lock の時は false
+static LOCKING: AtomicBool = AtomicBool::new(true);
+
+pub fn disable_locking() {
+    LOCKING.store(false, Ordering::Relaxed);
+}
+
+// 他の人がロックを持っている時だけ Locked にする (ロックに対応していないファイルシステムなどはそのままのエラー)
+fn lock_error(path: &OsStr, e: std::fs::TryLockError) -> MarError {
+    let path = path.to_string_lossy().into_owned();
+    match e {
+        std::fs::TryLockError::WouldBlock => MarError::Locked { path },
+        std::fs::TryLockError::Error(source) => MarError::File { path, source },
+    }
+}
+
+// アーカイブを読む時は共有ロックを取る。書き込み中のアーカイブを読もうとしたら待たずにエラーにする
+pub fn open_shared(path: &OsStr) -> Result<File, MarError> {
+    let file = File::open(path)?;
+    if LOCKING.load(Ordering::Relaxed) {
+        file.try_lock_shared().map_err(|e| lock_error(path, e))?;
+    }
+    Ok(file)
+}
+
+// 書く時は排他ロックを取ってから中身を消す (File::create だとロックを取る前に他の人が書いている途中のものを消してしまう)
+pub fn create_exclusive(path: &OsStr) -> Result<File, MarError> {
+    let file = std::fs::OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+    if LOCKING.load(Ordering::Relaxed) {
+        file.try_lock().map_err(|e| lock_error(path, e))?;
+    }
+    file.set_len(0)?;
+    Ok(file)
+}
+
+// entry の file_index に応じて .dat を開く。一度開いたものは取っておく
+pub struct DatFiles {
+    archive: OsString,
+    files: HashMap<u32, File>,
+}
+
+impl DatFiles {
+    pub fn new(archive: &OsStr) -> Self {
+        DatFiles { archive: archive.to_os_string(), files: HashMap::new() }
+    }
+
+    pub fn get(&mut self, file_index: u32) -> Result<&mut File, MarError> {
+        if !self.files.contains_key(&file_index) {
+            let file = open_shared(&dat_file_path(&self.archive, file_index))?;
+            self.files.insert(file_index, file);
+        }
+        Ok(self.files.get_mut(&file_index).unwrap())
+    }
+}
@@ -0,0 +1,6 @@
+pub mod proto;
+pub mod format;
+pub mod error;
+mod reader;
+
+pub use reader::MarReader;
@@ -1,11 +1,20 @@
 use clap::{Parser, Subcommand};
 
-mod proto;
+use mayakashi::{error, format, proto};
+
 mod cmd;
-mod format;
+mod throttle;
 
 #[derive(Parser)]
 struct Cli {
+    /// Don't take advisory locks on archive files (shared when reading, exclusive when writing)
+    #[arg(long, global = true)]
+    no_lock: bool,
+
+    /// How commands that read .dat files (extract, cat, verify, list) read them: seek reads each body with read(2), mmap memory-maps the files
+    #[arg(long, global = true, value_enum, default_value_t = cmd::ReadStrategy::Seek)]
+    read_strategy: cmd::ReadStrategy,
+
     #[clap(subcommand)]
     subcommand: SubCommands,
 }
@@ -14,12 +23,36 @@ struct Cli {
 enum SubCommands {
     Create(cmd::create::Args),
     ShowSum(cmd::showsum::Args),
+    Recompress(cmd::recompress::Args),
+    Which(cmd::which::Args),
+    VerifyTree(cmd::verify_tree::Args),
+    Repack(cmd::repack::Args),
+    Extract(cmd::extract::Args),
+    Cat(cmd::cat::Args),
+    Verify(cmd::verify::Args),
+    List(cmd::list::Args),
 }
 
 fn main() {
     let cli = Cli::parse();
-    match cli.subcommand {
+    if cli.no_lock {
+        format::disable_locking();
+    }
+    cmd::set_read_strategy(cli.read_strategy);
+    let result = match cli.subcommand {
         SubCommands::Create(args) => cmd::create::main(args),
         SubCommands::ShowSum(args) => cmd::showsum::main(args),
+        SubCommands::Recompress(args) => cmd::recompress::main(args),
+        SubCommands::Which(args) => cmd::which::main(args),
+        SubCommands::VerifyTree(args) => cmd::verify_tree::main(args),
+        SubCommands::Repack(args) => cmd::repack::main(args),
+        SubCommands::Extract(args) => cmd::extract::main(args),
+        SubCommands::Cat(args) => cmd::cat::main(args),
+        SubCommands::Verify(args) => cmd::verify::main(args),
+        SubCommands::List(args) => cmd::list::main(args),
+    };
+    if let Err(e) = result {
+        eprintln!("error: {}", e);
+        std::process::exit(1);
     }
 }
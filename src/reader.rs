@@ -0,0 +1,208 @@
+use std::{collections::HashMap, ffi::{OsStr, OsString}, fs::File, io::{Read, Seek, SeekFrom}, sync::{Arc, Mutex}};
+
+use crate::{error::MarError, format::{self, body, index_file}, proto};
+
+// アーカイブを開いて、中のファイルを好きな順番で読む
+pub struct MarReader {
+    index: proto::FileIndexFile,
+    dats: Dats,
+    // solid blob は中のファイルごとに展開し直さないよう、直前に展開した blob を取っておく
+    last_blob: Mutex<Option<((u32, u64), Vec<u8>)>>,
+}
+
+impl MarReader {
+    // archive は create --output に渡した名前 (.mar.idx などを除いたもの)
+    pub fn open(archive: impl AsRef<OsStr>) -> Result<Self, MarError> {
+        let archive = archive.as_ref();
+        let index = index_file::parse_index_file(&mut format::open_shared(&format::idx_file_path(archive))?)?;
+        Ok(MarReader {
+            index,
+            dats: Dats::Files(Mutex::new(format::DatFiles::new(archive))),
+            last_blob: Mutex::new(None),
+        })
+    }
+
+    // open と同じだが、.dat を mmap して読む (チャンクごとの read や確保をしないので、ランダムアクセスが速い)
+    pub fn open_mmap(archive: impl AsRef<OsStr>) -> Result<Self, MarError> {
+        let archive = archive.as_ref();
+        let index = index_file::parse_index_file(&mut format::open_shared(&format::idx_file_path(archive))?)?;
+        Ok(MarReader {
+            index,
+            dats: Dats::Mapped { archive: archive.to_os_string(), maps: Mutex::new(HashMap::new()) },
+            last_blob: Mutex::new(None),
+        })
+    }
+
+    pub fn index(&self) -> &proto::FileIndexFile {
+        &self.index
+    }
+
+    pub fn entries(&self) -> &[proto::FileEntry] {
+        &self.index.entries
+    }
+
+    // 先頭の / (Windows で作ったものなら \) はあっても無くても良い
+    pub fn find(&self, path: &str) -> Option<&proto::FileEntry> {
+        let path = path.trim_start_matches(['/', '\\']);
+        self.index.entries.iter().find(|e| e.info.as_ref().unwrap().path.trim_start_matches(['/', '\\']) == path)
+    }
+
+    // 展開して original_crc32/original_sha256 を確認したファイルの中身を返す
+    pub fn read_file(&self, entry: &proto::FileEntry) -> Result<Vec<u8>, MarError> {
+        let info = entry.info.as_ref().unwrap();
+        let decoded = if !entry.inline_body.is_empty() {
+            body::decode_entry_body(entry, &entry.inline_body, &self.index.zstd_dictionary)?
+        } else if entry.body_size == 0 {
+            Vec::new()
+        } else if entry.in_solid_blob {
+            self.read_solid(entry)?
+        } else {
+            self.dats.with_bytes(entry, 0, entry.body_size, |compressed| body::decode_entry_body(entry, compressed, &self.index.zstd_dictionary))?
+        };
+        body::verify_original(info, &decoded)?;
+        Ok(decoded)
+    }
+
+    // 圧縮されたままの body (inline_body か .dat の中身) を返す。solid blob なら blob 全体
+    pub fn read_compressed(&self, entry: &proto::FileEntry) -> Result<Vec<u8>, MarError> {
+        if !entry.inline_body.is_empty() {
+            return Ok(entry.inline_body.clone());
+        }
+        self.dats.with_bytes(entry, 0, entry.body_size, |compressed| Ok(compressed.to_vec()))
+    }
+
+    // ファイルの中身をチャンクごとに f に渡す。チャンクに分かれたファイルでもメモリはチャンク1つ分しか使わない
+    // 全部渡し終わってから CRC32 を確認するので、f は壊れたデータを受け取ることがある (その時は最後にエラーを返す)
+    pub fn for_each_chunk(&self, entry: &proto::FileEntry, mut f: impl FnMut(&[u8]) -> Result<(), MarError>) -> Result<(), MarError> {
+        // inline と solid blob の中身は小さい (solid blob でも 8MiB まで) のでまとめて展開する
+        if !entry.inline_body.is_empty() || entry.in_solid_blob || entry.body_size == 0 {
+            return f(&self.read_file(entry)?);
+        }
+
+        let info = entry.info.as_ref().unwrap();
+        let mut crc32 = crc32fast::Hasher::new();
+        for (chunk, offset) in info.chunks.iter().zip(body::chunk_offsets(info)) {
+            if offset.compressed_offset + chunk.compressed_length as u64 > entry.body_size {
+                return Err(MarError::Corrupt { detail: format!("{}: chunk runs past the end of the body", info.path) });
+            }
+            let decoded = self.dats.with_bytes(entry, offset.compressed_offset, chunk.compressed_length as u64, |compressed| body::decode_chunk(chunk, compressed, &self.index.zstd_dictionary))?;
+            crc32.update(&decoded);
+            f(&decoded)?;
+        }
+        if crc32.finalize() != info.original_crc32 {
+            return Err(MarError::ChecksumMismatch { path: info.path.clone() });
+        }
+        Ok(())
+    }
+
+    // ファイルの [start, end) だけを返す。チャンクに分かれたファイルなら、その範囲に掛かるチャンクだけを読んで展開する
+    // ファイル全体を見ないので original_crc32/original_sha256 は確認しない
+    pub fn read_range(&self, entry: &proto::FileEntry, start: u64, end: u64) -> Result<Vec<u8>, MarError> {
+        let info = entry.info.as_ref().unwrap();
+        let size = body::original_size(entry);
+        if start > end || end > size {
+            return Err(MarError::OutOfRange { path: info.path.clone(), start, end, size });
+        }
+        if start == end {
+            return Ok(Vec::new());
+        }
+
+        // inline と solid blob はどのみち全体を展開する
+        if !entry.inline_body.is_empty() || entry.in_solid_blob {
+            let decoded = if entry.inline_body.is_empty() { self.read_solid(entry)? } else { body::decode_entry_body(entry, &entry.inline_body, &self.index.zstd_dictionary)? };
+            return Ok(decoded[start as usize..end as usize].to_vec());
+        }
+
+        let mut range = Vec::with_capacity((end - start) as usize);
+        for (chunk, offset) in info.chunks.iter().zip(body::chunk_offsets(info)) {
+            let chunk_start = offset.original_offset;
+            let chunk_end = chunk_start + chunk.original_length as u64;
+            if chunk_end <= start {
+                continue;
+            }
+            if chunk_start >= end {
+                break;
+            }
+            if offset.compressed_offset + chunk.compressed_length as u64 > entry.body_size {
+                return Err(MarError::Corrupt { detail: format!("{}: chunk runs past the end of the body", info.path) });
+            }
+            let decoded = self.dats.with_bytes(entry, offset.compressed_offset, chunk.compressed_length as u64, |compressed| body::decode_chunk(chunk, compressed, &self.index.zstd_dictionary))?;
+            let from = start.max(chunk_start) - chunk_start;
+            let to = end.min(chunk_end) - chunk_start;
+            range.extend_from_slice(&decoded[from as usize..to as usize]);
+        }
+        Ok(range)
+    }
+
+    fn read_solid(&self, entry: &proto::FileEntry) -> Result<Vec<u8>, MarError> {
+        let info = entry.info.as_ref().unwrap();
+        let key = (entry.file_index, entry.body_offset);
+        let mut last_blob = self.last_blob.lock().unwrap();
+        let blob = match last_blob.take() {
+            Some((k, blob)) if k == key => blob,
+            _ => self.dats.with_bytes(entry, 0, entry.body_size, |compressed| body::decode_body(info, compressed, &self.index.zstd_dictionary))?,
+        };
+        let start = entry.solid_offset as usize;
+        let end = start + entry.solid_length as usize;
+        let decoded = blob.get(start..end).map(|d| d.to_vec());
+        *last_blob = Some((key, blob));
+        decoded.ok_or_else(|| MarError::Corrupt { detail: format!("{}: solid range is outside of the blob", info.path) })
+    }
+}
+
+enum Dats {
+    Files(Mutex<format::DatFiles>),
+    // File はロックを持ち続けるために一緒に取っておく
+    Mapped { archive: OsString, maps: Mutex<HashMap<u32, Arc<(File, memmap2::Mmap)>>> },
+}
+
+impl Dats {
+    // entry の body の [offset, offset + len) を f に渡す
+    fn with_bytes<R>(&self, entry: &proto::FileEntry, offset: u64, len: u64, f: impl FnOnce(&[u8]) -> Result<R, MarError>) -> Result<R, MarError> {
+        match self {
+            Dats::Files(dats) => {
+                let bytes = {
+                    let mut dats = dats.lock().unwrap();
+                    let dat = dats.get(entry.file_index)?;
+                    // body_size を信用して確保する前に、.dat に収まっているか確かめる
+                    let size = dat.metadata()?.len();
+                    let start = entry.body_offset.checked_add(offset);
+                    let end = start.and_then(|start| start.checked_add(len));
+                    let start = match (start, end) {
+                        (Some(start), Some(end)) if end <= size => start,
+                        _ => return Err(MarError::Corrupt { detail: format!("{}: body runs past the end of the .dat ({} bytes)", entry.info.as_ref().unwrap().path, size) }),
+                    };
+                    let mut bytes = vec![0; len as usize];
+                    dat.seek(SeekFrom::Start(start))?;
+                    dat.read_exact(&mut bytes)?;
+                    bytes
+                };
+                f(&bytes)
+            },
+            Dats::Mapped { archive, maps } => {
+                let map = {
+                    let mut maps = maps.lock().unwrap();
+                    match maps.get(&entry.file_index) {
+                        Some(map) => map.clone(),
+                        None => {
+                            let file = format::open_shared(&format::dat_file_path(archive, entry.file_index))?;
+                            // 他のプロセスが書き換えると読んだ中身が変わるが、書く側は排他ロックを取るので共有ロックを持っている間は起きない
+                            let map = unsafe { memmap2::Mmap::map(&file)? };
+                            let map = Arc::new((file, map));
+                            maps.insert(entry.file_index, map.clone());
+                            map
+                        },
+                    }
+                };
+                // .dat が途中で切れていたら読み出す前にエラーにする
+                let start = entry.body_offset.checked_add(offset);
+                let end = start.and_then(|start| start.checked_add(len));
+                let bytes = match (start, end) {
+                    (Some(start), Some(end)) if end <= map.1.len() as u64 => &map.1[start as usize..end as usize],
+                    _ => return Err(MarError::Corrupt { detail: format!("{}: body runs past the end of the .dat ({} bytes)", entry.info.as_ref().unwrap().path, map.1.len()) }),
+                };
+                f(bytes)
+            },
+        }
+    }
+}
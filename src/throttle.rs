@@ -0,0 +1,44 @@
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+// --throttle 用のトークンバケツ。スレッド間で共有して、読み書きの合計をだいたい rate バイト/秒に抑える
+pub struct Throttle {
+    rate: u64,
+    state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    // 負の時は借金 (大きな読み書きは先にやってしまって後で待つ)
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Throttle {
+    pub fn new(rate: u64) -> Self {
+        Throttle {
+            rate,
+            state: Mutex::new(ThrottleState { tokens: rate as f64, last_refill: Instant::now() }),
+        }
+    }
+
+    // bytes バイト読み書きしたことにして、速すぎたらその分だけ待つ
+    pub fn consume(&self, bytes: usize) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+            // 溜められるのは1秒分まで
+            state.tokens = (state.tokens + elapsed * self.rate as f64).min(self.rate as f64);
+            state.last_refill = now;
+            state.tokens -= bytes as f64;
+            if state.tokens < 0.0 {
+                Duration::from_secs_f64(-state.tokens / self.rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+        // lock を持ったまま寝ないようにする
+        if !wait.is_zero() {
+            std::thread::sleep(wait);
+        }
+    }
+}
@@ -3,6 +3,7 @@ import os
 import subprocess
 import time
 import glob
+import shutil
 
 def make_test_source(srcdir: str):
     files = {
@@ -115,8 +116,711 @@ def run_test(mountdir: str, overlaydir: str | None):
 
     print("Test Done!")
 
+def test_skippable_frame_headers(tmpdir: str):
+    print("Test - --skippable-frame-headers の body を zstd コマンドで展開できる")
+    if shutil.which("zstd") is None:
+        print("zstd command not found, skipping")
+        return
+    srcdir = os.path.join(tmpdir, 'frames_src')
+    os.mkdir(srcdir)
+    # 複数チャンクになるように 8MiB より大きくする
+    content = b"".join(b"line %d\n" % i for i in range(2 * 1024 * 1024))
+    with open(os.path.join(srcdir, 'big.bin'), 'wb') as f:
+        f.write(content)
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'frames'),
+        "-j", "1",
+        "--zstd-frames",
+        "--skippable-frame-headers",
+    ]).check_returncode()
+    # ファイルが1つなので .dat はその body そのもの
+    decoded = subprocess.run(["zstd", "-dc", os.path.join(tmpdir, 'frames.mar.dat')], stdout=subprocess.PIPE)
+    decoded.check_returncode()
+    assert decoded.stdout == content
+
+def test_reproducible(tmpdir: str):
+    print("Test - --reproducible なら何スレッドで作っても同じバイト列になる")
+    srcdir = os.path.join(tmpdir, 'reproducible_src')
+    os.mkdir(srcdir)
+    for i in range(200):
+        with open(os.path.join(srcdir, 'file%03d.bin' % i), 'wb') as f:
+            # 圧縮にかかる時間がばらつくように大きさと中身を変える
+            f.write(os.urandom(i * 1024) + b"x" * (i * 4096))
+    # dedup の canonical も順番で決まることを確認する
+    with open(os.path.join(srcdir, 'file000.bin'), 'rb') as f, open(os.path.join(srcdir, 'zzz.dup.bin'), 'wb') as g:
+        g.write(f.read())
+    outputs = []
+    for n in range(3):
+        output = os.path.join(tmpdir, 'reproducible%d' % n)
+        subprocess.run([
+            "./mayakashi.exe",
+            "create",
+            "-i", srcdir,
+            "-o", output,
+            "-j", "16",
+            "--dedup",
+            "--reproducible",
+        ]).check_returncode()
+        with open(output + '.mar.idx', 'rb') as f:
+            idx = f.read()
+        with open(output + '.mar.dat', 'rb') as f:
+            dat = f.read()
+        outputs.append((idx, dat))
+    for output in outputs[1:]:
+        assert output == outputs[0]
+
+def test_cat_range(tmpdir: str):
+    print("Test - cat --start/--end でチャンクをまたぐ範囲を読める")
+    srcdir = os.path.join(tmpdir, 'range_src')
+    os.mkdir(srcdir)
+    # 512KiB ごとのチャンクに分かれるように 8MiB より大きくする
+    content = b"".join(b"line %d\n" % i for i in range(1024 * 1024))
+    assert len(content) > 8 * 1024 * 1024
+    with open(os.path.join(srcdir, 'big.txt'), 'wb') as f:
+        f.write(content)
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'range'),
+        "-j", "1",
+    ]).check_returncode()
+    chunk = 512 * 1024
+    ranges = [
+        (0, 10), # 最初のチャンクの中
+        (chunk - 10, chunk + 10), # 2つのチャンクにまたがる
+        (chunk - 10, 2 * chunk + 10), # 3つのチャンクにまたがる
+        (chunk, 2 * chunk), # ちょうどチャンク1つ分
+        (len(content) - 10, len(content)), # 最後
+        (0, len(content)), # 全体
+    ]
+    for start, end in ranges:
+        for mmap in [[], ["--mmap"]]:
+            result = subprocess.run([
+                "./mayakashi.exe",
+                "cat",
+                "-i", os.path.join(tmpdir, 'range'),
+                "--start", str(start),
+                "--end", str(end),
+                "big.txt",
+            ] + mmap, stdout=subprocess.PIPE)
+            result.check_returncode()
+            assert result.stdout == content[start:end], (start, end, mmap)
+    # ファイルの外は読めない
+    result = subprocess.run([
+        "./mayakashi.exe",
+        "cat",
+        "-i", os.path.join(tmpdir, 'range'),
+        "--start", "0",
+        "--end", str(len(content) + 1),
+        "big.txt",
+    ], stdout=subprocess.PIPE)
+    assert result.returncode != 0
+
+def test_non_utf8_path(tmpdir: str):
+    print("Test - UTF-8 でないファイル名も元のバイト列のまま展開できる")
+    if os.name == 'nt':
+        print("not on unix, skipping")
+        return
+    srcdir = os.fsencode(os.path.join(tmpdir, 'non_utf8_src'))
+    os.mkdir(srcdir)
+    # Latin-1 の "café" (0xe9 は UTF-8 として不正)
+    files = {
+        b'caf\xe9.txt': b"hello\n",
+        os.path.join(b'dir\xff', b'inner.txt'): b"inner\n",
+    }
+    os.mkdir(os.path.join(srcdir, b'dir\xff'))
+    os.mkdir(os.path.join(srcdir, b'empty\xfe'))
+    for name, content in files.items():
+        with open(os.path.join(srcdir, name), 'wb') as f:
+            f.write(content)
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'non_utf8'),
+        "-j", "1",
+    ]).check_returncode()
+    outdir = os.fsencode(os.path.join(tmpdir, 'non_utf8_out'))
+    subprocess.run([
+        "./mayakashi.exe",
+        "extract",
+        "-i", os.path.join(tmpdir, 'non_utf8'),
+        "-o", outdir,
+    ]).check_returncode()
+    for name, content in files.items():
+        with open(os.path.join(outdir, name), 'rb') as f:
+            assert f.read() == content, name
+    assert os.path.isdir(os.path.join(outdir, b'empty\xfe'))
+    assert sorted(os.listdir(outdir)) == sorted([b'caf\xe9.txt', b'dir\xff', b'empty\xfe'])
+
+def test_input_trailing_slash(tmpdir: str):
+    print("Test - --input の末尾に区切りがあっても無くても同じパスで保存される")
+    srcdir = os.path.join(tmpdir, 'slash_src')
+    os.makedirs(os.path.join(srcdir, 'sub'))
+    with open(os.path.join(srcdir, 'top.txt'), 'wb') as f:
+        f.write(b"top\n")
+    with open(os.path.join(srcdir, 'sub', 'inner.txt'), 'wb') as f:
+        f.write(b"inner\n")
+    listings = []
+    for name, input in [('no_slash', srcdir), ('slash', srcdir + os.sep)]:
+        subprocess.run([
+            "./mayakashi.exe",
+            "create",
+            "-i", input,
+            "-o", os.path.join(tmpdir, name),
+            "-j", "1",
+        ]).check_returncode()
+        result = subprocess.run([
+            "./mayakashi.exe",
+            "list",
+            "-i", os.path.join(tmpdir, name),
+        ], stdout=subprocess.PIPE)
+        result.check_returncode()
+        # 最後の行は合計。パスの後ろの " (inline)" などは除く
+        paths = [line.split(b"\t")[4].split(b" (")[0] for line in result.stdout.splitlines()[:-1]]
+        assert sorted(paths) == [b"/sub/inner.txt", b"/top.txt"], (name, paths)
+        listings.append(result.stdout)
+    assert listings[0] == listings[1]
+
+def test_best(tmpdir: str):
+    print("Test - --best で方式が混ざったファイルも元に戻る")
+    srcdir = os.path.join(tmpdir, 'best_src')
+    os.mkdir(srcdir)
+    # 圧縮できないチャンクとよく縮むチャンクが1つのファイルに混ざるようにする
+    mixed = os.urandom(1024 * 1024) + b"".join(b"line %d\n" % i for i in range(1024 * 1024))
+    files = {
+        'mixed.bin': mixed,
+        'small.txt': b"hello " * 1000,
+        'random.bin': os.urandom(4096),
+    }
+    for name, content in files.items():
+        with open(os.path.join(srcdir, name), 'wb') as f:
+            f.write(content)
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'best'),
+        "-j", "1",
+        "--best",
+    ]).check_returncode()
+    outdir = os.path.join(tmpdir, 'best_out')
+    subprocess.run([
+        "./mayakashi.exe",
+        "extract",
+        "-i", os.path.join(tmpdir, 'best'),
+        "-o", outdir,
+    ]).check_returncode()
+    for name, content in files.items():
+        with open(os.path.join(outdir, name), 'rb') as f:
+            assert f.read() == content, name
+
+def test_codec_brotli(tmpdir: str):
+    print("Test - --codec brotli で作ったものを extract で元に戻せる")
+    srcdir = os.path.join(tmpdir, 'brotli_src')
+    os.mkdir(srcdir)
+    files = {
+        'big.js': b"".join(b"function f%d() { return %d; }\n" % (i, i) for i in range(100000)),
+        'small.html': b"<p>hello</p>\n" * 1000,
+        'random.bin': os.urandom(4096),
+    }
+    for name, content in files.items():
+        with open(os.path.join(srcdir, name), 'wb') as f:
+            f.write(content)
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'brotli'),
+        "--codec", "brotli",
+    ]).check_returncode()
+    outdir = os.path.join(tmpdir, 'brotli_out')
+    subprocess.run([
+        "./mayakashi.exe",
+        "extract",
+        "-i", os.path.join(tmpdir, 'brotli'),
+        "-o", outdir,
+    ]).check_returncode()
+    for name, content in files.items():
+        with open(os.path.join(outdir, name), 'rb') as f:
+            assert f.read() == content, name
+
+def test_skip_compressed(tmpdir: str):
+    print("Test - 圧縮済みの拡張子のファイルは --no-skip-compressed を付けない限りそのまま入る")
+    srcdir = os.path.join(tmpdir, 'skip_src')
+    os.mkdir(srcdir)
+    content = b"not really a jpeg " * 10000
+    with open(os.path.join(srcdir, 'photo.JPG'), 'wb') as f:
+        f.write(content)
+    for name, flags in [('skip', []), ('noskip', ["--no-skip-compressed"])]:
+        subprocess.run([
+            "./mayakashi.exe",
+            "create",
+            "-i", srcdir,
+            "-o", os.path.join(tmpdir, name),
+        ] + flags).check_returncode()
+    assert os.path.getsize(os.path.join(tmpdir, 'skip.mar.dat')) == len(content)
+    assert os.path.getsize(os.path.join(tmpdir, 'noskip.mar.dat')) < len(content)
+
+def test_dictionary(tmpdir: str):
+    print("Test - --dictionary で作ったものを extract で元に戻せる")
+    srcdir = os.path.join(tmpdir, 'dictionary_src')
+    os.mkdir(srcdir)
+    files = {}
+    for i in range(500):
+        files['config%d.json' % i] = b'{"id": %d, "name": "item %d", "enabled": %s, "tags": ["alpha", "beta"]}\n' % (i, i, b"true" if i % 2 else b"false")
+    files['big.bin'] = os.urandom(9 * 1024 * 1024)
+    for name, content in files.items():
+        with open(os.path.join(srcdir, name), 'wb') as f:
+            f.write(content)
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'dictionary'),
+        "--dictionary",
+    ]).check_returncode()
+    outdir = os.path.join(tmpdir, 'dictionary_out')
+    subprocess.run([
+        "./mayakashi.exe",
+        "extract",
+        "-i", os.path.join(tmpdir, 'dictionary'),
+        "-o", outdir,
+    ]).check_returncode()
+    for name, content in files.items():
+        with open(os.path.join(outdir, name), 'rb') as f:
+            assert f.read() == content, name
+
+def test_recompress_empty_and_symlink(tmpdir: str):
+    print("Test - 空のファイルと symlink があっても recompress したものが元に戻る")
+    srcdir = os.path.join(tmpdir, 'recompress_src')
+    os.mkdir(srcdir)
+    # 空のファイルは次の body と同じ offset になり、symlink は offset 0 のまま
+    files = {
+        'a_empty.txt': b"",
+        'b.txt': b"first body " * 1000,
+        'd.txt': b"second body " * 1000,
+    }
+    for name, content in files.items():
+        with open(os.path.join(srcdir, name), 'wb') as f:
+            f.write(content)
+    os.symlink('b.txt', os.path.join(srcdir, 'c_link'))
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'recompress_before'),
+    ]).check_returncode()
+    subprocess.run([
+        "./mayakashi.exe",
+        "recompress",
+        "-i", os.path.join(tmpdir, 'recompress_before'),
+        "-o", os.path.join(tmpdir, 'recompress_after'),
+        "--codec", "lz4",
+    ]).check_returncode()
+    outdir = os.path.join(tmpdir, 'recompress_out')
+    subprocess.run([
+        "./mayakashi.exe",
+        "extract",
+        "-i", os.path.join(tmpdir, 'recompress_after'),
+        "-o", outdir,
+    ]).check_returncode()
+    for name, content in files.items():
+        with open(os.path.join(outdir, name), 'rb') as f:
+            assert f.read() == content, name
+    assert os.readlink(os.path.join(outdir, 'c_link')) == 'b.txt'
+
+def test_extract_symlink_escape(tmpdir: str):
+    print("Test - symlink の後に同じ場所の下のファイルがある悪意のあるアーカイブでも、output の外には書かない")
+    srcdir = os.path.join(tmpdir, 'escape_src')
+    outside = os.path.join(tmpdir, 'escape_outside')
+    os.makedirs(os.path.join(srcdir, 'b'))
+    os.mkdir(outside)
+    os.symlink(outside, os.path.join(srcdir, 'a'))
+    with open(os.path.join(srcdir, 'b', 'x'), 'wb') as f:
+        f.write(b"pwned")
+    archive = os.path.join(tmpdir, 'escape')
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", archive,
+        "--index-codec", "passthrough",
+    ]).check_returncode()
+    # 圧縮していない index の中の "/b/x" を "/a/x" に書き換えて、symlink /a の下にファイルがあるアーカイブにする
+    with open(archive + '.mar.idx', 'rb') as f:
+        index = f.read()
+    assert index.count(b"/b/x") == 1
+    with open(archive + '.mar.idx', 'wb') as f:
+        f.write(index.replace(b"/b/x", b"/a/x"))
+    result = subprocess.run([
+        "./mayakashi.exe",
+        "extract",
+        "-i", archive,
+        "-o", os.path.join(tmpdir, 'escape_out'),
+    ])
+    assert result.returncode != 0
+    assert not os.path.exists(os.path.join(outside, 'x'))
+
+def test_audit_symlink(tmpdir: str):
+    print("Test - symlink があっても create --audit が通る")
+    srcdir = os.path.join(tmpdir, 'audit_src')
+    os.mkdir(srcdir)
+    with open(os.path.join(srcdir, 'file.txt'), 'wb') as f:
+        f.write(b"hello " * 100)
+    os.symlink('file.txt', os.path.join(srcdir, 'link'))
+    os.symlink('missing.txt', os.path.join(srcdir, 'dangling'))
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'audit'),
+        "--audit",
+    ]).check_returncode()
+
+def test_reproducible_unreadable(tmpdir: str):
+    print("Test - --reproducible --batch で読めないファイルがあっても、止まらずに失敗する")
+    workdir = os.path.join(tmpdir, 'unreadable')
+    srcdir = os.path.join(workdir, 'src')
+    os.makedirs(srcdir)
+    for i in range(64):
+        with open(os.path.join(srcdir, 'file%03d.bin' % i), 'wb') as f:
+            f.write(os.urandom(i * 1024))
+    os.chmod(os.path.join(srcdir, 'file010.bin'), 0)
+    # root は chmod 0 でも読めてしまうので nobody で動かす
+    user = None
+    if os.geteuid() == 0:
+        os.chmod(tmpdir, 0o755)
+        os.chmod(workdir, 0o777)
+        user = 65534
+    result = subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(workdir, 'out'),
+        "-j", "4",
+        "--batch", "8",
+        "--reproducible",
+    ], user=user, timeout=120)
+    assert result.returncode != 0
+
+def test_verify_tree_symlink_solid(tmpdir: str):
+    print("Test - symlink と solid blob の中のファイルがあっても、作った直後の verify-tree は差分なし")
+    srcdir = os.path.join(tmpdir, 'verify_tree_src')
+    os.mkdir(srcdir)
+    for i in range(10):
+        with open(os.path.join(srcdir, 'small%d.txt' % i), 'wb') as f:
+            f.write(b"small file %d\n" % i * 100)
+    os.symlink('small0.txt', os.path.join(srcdir, 'link'))
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'verify_tree'),
+        "--solid-under", "1M",
+    ]).check_returncode()
+    subprocess.run([
+        "./mayakashi.exe",
+        "verify-tree",
+        "--index", os.path.join(tmpdir, 'verify_tree.mar.idx'),
+        "--dir", srcdir,
+    ]).check_returncode()
+    # リンク先を変えたら差分になる
+    os.remove(os.path.join(srcdir, 'link'))
+    os.symlink('small1.txt', os.path.join(srcdir, 'link'))
+    result = subprocess.run([
+        "./mayakashi.exe",
+        "verify-tree",
+        "--index", os.path.join(tmpdir, 'verify_tree.mar.idx'),
+        "--dir", srcdir,
+    ], capture_output=True)
+    assert result.returncode != 0
+    assert b"M /link" in result.stdout, result.stdout
+
+def test_throttle_zero(tmpdir: str):
+    print("Test - --throttle 0 は panic せずに引数のエラーになる")
+    srcdir = os.path.join(tmpdir, 'throttle_src')
+    os.mkdir(srcdir)
+    with open(os.path.join(srcdir, 'a.txt'), 'wb') as f:
+        f.write(b"throttle\n" * 100)
+    result = subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'throttle'),
+        "--throttle", "0",
+    ], capture_output=True)
+    # clap の引数エラーは 2 (panic は 101)
+    assert result.returncode == 2, result.stderr
+    assert not os.path.exists(os.path.join(tmpdir, 'throttle.mar.idx'))
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'throttle'),
+        "--throttle", "1MiB",
+    ]).check_returncode()
+
+def test_paths_from_archive_escape(tmpdir: str):
+    print("Test - --paths-from-archive の index に .. があっても、input の外のファイルは読まない")
+    srcdir = os.path.join(tmpdir, 'pfa_src')
+    os.makedirs(os.path.join(srcdir, 'zz'))
+    with open(os.path.join(srcdir, 'zz', 'pfa_secret.txt'), 'wb') as f:
+        f.write(b"inside")
+    with open(os.path.join(tmpdir, 'pfa_secret.txt'), 'wb') as f:
+        f.write(b"outside")
+    archive = os.path.join(tmpdir, 'pfa_old')
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", archive,
+        "--index-codec", "passthrough",
+    ]).check_returncode()
+    # "/zz/pfa_secret.txt" を "/../pfa_secret.txt" に書き換える
+    with open(archive + '.mar.idx', 'rb') as f:
+        index = f.read()
+    assert index.count(b"/zz/pfa") == 1
+    with open(archive + '.mar.idx', 'wb') as f:
+        f.write(index.replace(b"/zz/pfa", b"/../pfa"))
+    result = subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", os.path.join(tmpdir, 'pfa_new'),
+        "--paths-from-archive", archive + '.mar.idx',
+    ], capture_output=True)
+    assert result.returncode != 0
+    assert b"path escapes the target directory" in result.stderr, result.stderr
+    assert not os.path.exists(os.path.join(tmpdir, 'pfa_new.mar.idx'))
+
+def test_list_total_with_empty_file(tmpdir: str):
+    print("Test - 空のファイルと同じ offset から始まる body も list の合計に入る")
+    srcdir = os.path.join(tmpdir, 'list_empty_src')
+    os.mkdir(srcdir)
+    open(os.path.join(srcdir, 'a_empty'), 'wb').close()
+    with open(os.path.join(srcdir, 'b.bin'), 'wb') as f:
+        f.write(os.urandom(4096))
+    archive = os.path.join(tmpdir, 'list_empty')
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", archive,
+        "-j", "1",
+    ]).check_returncode()
+    result = subprocess.run([
+        "./mayakashi.exe",
+        "list",
+        "-i", archive,
+    ], stdout=subprocess.PIPE)
+    result.check_returncode()
+    # 最後の行は "2 files, <original> -> <stored> (...%)"
+    stored = int(result.stdout.splitlines()[-1].split(b" -> ")[1].split(b" ")[0])
+    assert stored == os.path.getsize(archive + '.mar.dat'), result.stdout
+
+def test_roundtrip_layouts(tmpdir: str):
+    print("Test - solid / inline / .dat 分割のアーカイブが verify を通り、repack しても extract で元に戻る")
+    srcdir = os.path.join(tmpdir, 'layouts_src')
+    os.makedirs(os.path.join(srcdir, 'sub'))
+    files = {
+        'empty': b"",
+        'tiny.txt': b"tiny\n",
+        'sub/small.txt': b"small file\n" * 200,
+        'sub/random.bin': os.urandom(300 * 1024),
+        'big.bin': os.urandom(9 * 1024 * 1024),
+    }
+    for name, content in files.items():
+        with open(os.path.join(srcdir, name), 'wb') as f:
+            f.write(content)
+    os.symlink('sub/small.txt', os.path.join(srcdir, 'link'))
+
+    def check_extract(archive: str, outdir: str):
+        subprocess.run([
+            "./mayakashi.exe",
+            "verify",
+            "-i", archive,
+        ]).check_returncode()
+        subprocess.run([
+            "./mayakashi.exe",
+            "extract",
+            "-i", archive,
+            "-o", outdir,
+        ]).check_returncode()
+        for name, content in files.items():
+            with open(os.path.join(outdir, name), 'rb') as f:
+                assert f.read() == content, (archive, name)
+        assert os.readlink(os.path.join(outdir, 'link')) == 'sub/small.txt', archive
+
+    archive = os.path.join(tmpdir, 'layouts')
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", archive,
+        "--inline-under", "16",
+        "--solid-under", "4K",
+        "--dat-split-size", "1M",
+    ]).check_returncode()
+    # 9MiB のファイルは 1MiB に収まらないので、それだけの .dat ができる
+    assert os.path.exists(archive + '.mar.1.dat')
+    check_extract(archive, os.path.join(tmpdir, 'layouts_out'))
+
+    repacked = os.path.join(tmpdir, 'layouts_repacked')
+    subprocess.run([
+        "./mayakashi.exe",
+        "repack",
+        "-i", archive,
+        "-o", repacked,
+        "--solid",
+    ]).check_returncode()
+    check_extract(repacked, os.path.join(tmpdir, 'layouts_repacked_out'))
+
+def test_extract_special_bits(tmpdir: str):
+    print("Test - extract は --preserve-special-bits が無いと setuid などを戻さない")
+    srcdir = os.path.join(tmpdir, 'special_bits_src')
+    os.mkdir(srcdir)
+    with open(os.path.join(srcdir, 'tool'), 'wb') as f:
+        f.write(b"#!/bin/sh\n")
+    os.chmod(os.path.join(srcdir, 'tool'), 0o4755)
+    archive = os.path.join(tmpdir, 'special_bits')
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", archive,
+    ]).check_returncode()
+    for flags, expected in [([], 0o755), (["--preserve-special-bits"], 0o4755)]:
+        outdir = os.path.join(tmpdir, 'special_bits_out%d' % len(flags))
+        subprocess.run([
+            "./mayakashi.exe",
+            "extract",
+            "-i", archive,
+            "-o", outdir,
+        ] + flags).check_returncode()
+        mode = os.stat(os.path.join(outdir, 'tool')).st_mode & 0o7777
+        assert mode == expected, (flags, oct(mode))
+
+def test_extract_directory_symlink(tmpdir: str):
+    print("Test - output に元からある symlink と同じ名前のディレクトリがあっても、リンク先には触らない")
+    srcdir = os.path.join(tmpdir, 'dir_link_src')
+    os.makedirs(os.path.join(srcdir, 'd'))
+    outside = os.path.join(tmpdir, 'dir_link_outside')
+    os.mkdir(outside)
+    os.utime(outside, (0, 0))
+    archive = os.path.join(tmpdir, 'dir_link')
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", archive,
+    ]).check_returncode()
+    outdir = os.path.join(tmpdir, 'dir_link_out')
+    os.mkdir(outdir)
+    os.symlink(outside, os.path.join(outdir, 'd'))
+    result = subprocess.run([
+        "./mayakashi.exe",
+        "extract",
+        "-i", archive,
+        "-o", outdir,
+    ])
+    assert result.returncode != 0
+    assert os.stat(outside).st_mtime == 0
+
+def test_read_strategy(tmpdir: str):
+    print("Test - --read-strategy mmap でも seek と同じように読める")
+    srcdir = os.path.join(tmpdir, 'read_strategy_src')
+    os.mkdir(srcdir)
+    content = os.urandom(9 * 1024 * 1024)
+    with open(os.path.join(srcdir, 'big.bin'), 'wb') as f:
+        f.write(content)
+    archive = os.path.join(tmpdir, 'read_strategy')
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", archive,
+    ]).check_returncode()
+    listings = []
+    for strategy in ["seek", "mmap"]:
+        subprocess.run([
+            "./mayakashi.exe",
+            "--read-strategy", strategy,
+            "verify",
+            "-i", archive,
+        ]).check_returncode()
+        result = subprocess.run([
+            "./mayakashi.exe",
+            "list",
+            "-i", archive,
+            "--read-strategy", strategy,
+        ], stdout=subprocess.PIPE)
+        result.check_returncode()
+        listings.append(result.stdout)
+        result = subprocess.run([
+            "./mayakashi.exe",
+            "cat",
+            "-i", archive,
+            "--read-strategy", strategy,
+            "/big.bin",
+        ], stdout=subprocess.PIPE)
+        result.check_returncode()
+        assert result.stdout == content, strategy
+    assert listings[0] == listings[1]
+
+def test_recompress_level(tmpdir: str):
+    print("Test - recompress の --level は codec ごとの範囲で確かめる")
+    srcdir = os.path.join(tmpdir, 'recompress_level_src')
+    os.mkdir(srcdir)
+    with open(os.path.join(srcdir, 'a.txt'), 'wb') as f:
+        f.write(b"recompress level\n" * 1000)
+    archive = os.path.join(tmpdir, 'recompress_level')
+    subprocess.run([
+        "./mayakashi.exe",
+        "create",
+        "-i", srcdir,
+        "-o", archive,
+    ]).check_returncode()
+    for i, (codec, level, ok) in enumerate([("lz4", "20", False), ("brotli", "12", False), ("passthrough", "1", False), ("zstd", "-1", True), ("lz4", "9", True), ("brotli", "5", True)]):
+        result = subprocess.run([
+            "./mayakashi.exe",
+            "recompress",
+            "-i", archive,
+            "-o", os.path.join(tmpdir, 'recompress_level_out%d' % i),
+            "--codec", codec,
+            "--level", level,
+        ])
+        assert (result.returncode == 0) == ok, (codec, level)
+
 def main():
     with tempfile.TemporaryDirectory() as tmpdir:
+        test_skippable_frame_headers(tmpdir)
+        test_reproducible(tmpdir)
+        test_cat_range(tmpdir)
+        test_non_utf8_path(tmpdir)
+        test_input_trailing_slash(tmpdir)
+        test_best(tmpdir)
+        test_codec_brotli(tmpdir)
+        test_skip_compressed(tmpdir)
+        test_dictionary(tmpdir)
+        test_recompress_empty_and_symlink(tmpdir)
+        test_extract_symlink_escape(tmpdir)
+        test_audit_symlink(tmpdir)
+        test_reproducible_unreadable(tmpdir)
+        test_verify_tree_symlink_solid(tmpdir)
+        test_throttle_zero(tmpdir)
+        test_paths_from_archive_escape(tmpdir)
+        test_list_total_with_empty_file(tmpdir)
+        test_roundtrip_layouts(tmpdir)
+        test_extract_special_bits(tmpdir)
+        test_extract_directory_symlink(tmpdir)
+        test_read_strategy(tmpdir)
+        test_recompress_level(tmpdir)
+
         srcdir = os.path.join(tmpdir, 'src')
         os.mkdir(srcdir)
 